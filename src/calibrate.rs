@@ -0,0 +1,242 @@
+use std::{
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use winit::{
+    application::ApplicationHandler,
+    event::{DeviceEvent, DeviceId, WindowEvent},
+    event_loop::{ActiveEventLoop, EventLoop},
+    keyboard::{KeyCode, PhysicalKey},
+    window::WindowId,
+};
+
+use crate::{
+    config::{self, StickSettings},
+    stick_from_motion, AXIS_MAX,
+};
+
+/// Guided `--calibrate` mode. It records a mouse movement the user wants to be one full in-game
+/// turn and works out which sensitivity gets that turn given how fast the game turns at full
+/// deflection. No controller is created so it works without a game running. The suggestion can
+/// be written to `save_path`.
+pub fn run(
+    event_loop: EventLoop<()>,
+    stick: &StickSettings,
+    save_path: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let (sensitivity, recenter) = (stick.sensitivity, stick.recenter_timeout());
+    let turn_secs: f64 = prompt(
+        "How many seconds does a full 360 degree turn take in the game with the stick fully pushed? ",
+    )?
+    .trim()
+    .parse()
+    .context("Expected a number of seconds")?;
+    anyhow::ensure!(
+        turn_secs > 0.,
+        "The turn time has to be more than 0 seconds"
+    );
+
+    println!("Press Space, move the mouse left or right across the desk distance you want a full turn to take,");
+    println!("then press Space again. Escape cancels.");
+
+    let mut calibration = Calibration::default();
+    event_loop.run_app(&mut calibration)?;
+    if !calibration.done {
+        println!("Calibration cancelled");
+        return Ok(());
+    }
+
+    let samples = calibration.samples;
+    anyhow::ensure!(!samples.is_empty(), "No mouse motion was recorded");
+
+    let outputs: Vec<f64> = samples
+        .iter()
+        .map(|(_, dx)| stick_x(*dx, sensitivity).abs())
+        .collect();
+    let average = outputs.iter().sum::<f64>() / outputs.len() as f64;
+    let max = outputs.iter().copied().fold(0., f64::max);
+    let degrees = turns(&samples, sensitivity, recenter, turn_secs) * 360.;
+    println!(
+        "Recorded {} motion events. At sensitivity {sensitivity} the stick averaged {average:.1} and peaked at {max:.1},",
+        samples.len()
+    );
+    println!("which turns about {degrees:.0} degrees in the game.");
+
+    let suggested = match suggest_sensitivity(&samples, recenter, turn_secs) {
+        Some(suggested) => suggested,
+        None => {
+            println!("Even the highest sensitivity can't turn a full circle with that movement, try making the movement take longer.");
+            return Ok(());
+        }
+    };
+    println!("Suggested sensitivity: {suggested:.1}");
+
+    let path = match save_path {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let answer = prompt(&format!(
+        "Write sensitivity = {suggested:.1} to the [stick] table in {}? [y/N] ",
+        path.display()
+    ))?;
+    if answer.trim().eq_ignore_ascii_case("y") {
+        config::set_value(
+            &path,
+            Some("stick"),
+            "sensitivity",
+            ((suggested * 10.).round() / 10.).into(),
+        )?;
+        println!("Saved");
+    }
+
+    Ok(())
+}
+
+fn prompt(question: &str) -> anyhow::Result<String> {
+    print!("{question}");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(answer)
+}
+
+fn stick_x(dx: f64, sensitivity: f64) -> f64 {
+    stick_from_motion((dx, 0.), sensitivity, 1.)
+        .0
+        .clamp(-AXIS_MAX as f64, AXIS_MAX as f64)
+}
+
+/// How many full turns the recorded motion makes in the game at a sensitivity. Each stick value
+/// is held until the next motion or the recenter timeout, whichever comes first.
+fn turns(samples: &[(Instant, f64)], sensitivity: f64, recenter: Duration, turn_secs: f64) -> f64 {
+    let mut turns = 0.;
+    for (i, (at, dx)) in samples.iter().enumerate() {
+        let held = samples
+            .get(i + 1)
+            .map_or(recenter, |(next, _)| *next - *at)
+            .min(recenter);
+        turns += stick_x(*dx, sensitivity) / AXIS_MAX as f64 * held.as_secs_f64() / turn_secs;
+    }
+
+    turns.abs()
+}
+
+/// Finds the sensitivity that makes the recorded motion one full turn
+fn suggest_sensitivity(
+    samples: &[(Instant, f64)],
+    recenter: Duration,
+    turn_secs: f64,
+) -> Option<f64> {
+    let (mut low, mut high) = (1_f64, 100_000_f64);
+    if turns(samples, high, recenter, turn_secs) < 1. {
+        return None;
+    }
+
+    // Turning only goes up with sensitivity so a bisection finds it
+    for _ in 0..60 {
+        let middle = (low * high).sqrt();
+        if turns(samples, middle, recenter, turn_secs) < 1. {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+
+    Some(high)
+}
+
+#[derive(Default)]
+struct Calibration {
+    key_held: bool,
+    recording: bool,
+    done: bool,
+    samples: Vec<(Instant, f64)>,
+}
+
+impl ApplicationHandler for Calibration {
+    fn resumed(&mut self, _: &ActiveEventLoop) {}
+
+    fn window_event(&mut self, _: &ActiveEventLoop, _: WindowId, _: WindowEvent) {}
+
+    fn device_event(&mut self, event_loop: &ActiveEventLoop, _: DeviceId, event: DeviceEvent) {
+        match event {
+            DeviceEvent::MouseMotion { delta } if self.recording => {
+                self.samples.push((Instant::now(), delta.0));
+            }
+            DeviceEvent::Key(event) => {
+                // Raw key events also repeat while held so only count the first press
+                let pressed = event.state.is_pressed();
+                if std::mem::replace(&mut self.key_held, pressed) || !pressed {
+                    return;
+                }
+
+                match event.physical_key {
+                    PhysicalKey::Code(KeyCode::Space) if self.recording => {
+                        self.done = true;
+                        event_loop.exit();
+                    }
+                    PhysicalKey::Code(KeyCode::Space) => {
+                        println!("Recording...");
+                        self.recording = true;
+                    }
+                    PhysicalKey::Code(KeyCode::Escape) => event_loop.exit(),
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECENTER: Duration = Duration::from_millis(20);
+
+    /// Motions of `dx` at these many milliseconds in
+    fn samples(dx: f64, times_ms: &[u64]) -> Vec<(Instant, f64)> {
+        let start = Instant::now();
+        times_ms
+            .iter()
+            .map(|ms| (start + Duration::from_millis(*ms), dx))
+            .collect()
+    }
+
+    #[test]
+    fn turns_hold_each_value_until_the_next_motion_or_recenter() {
+        // Fully pushed the whole time, so it's only the held time over the turn time
+        let turn_secs = 0.1;
+        let close = samples(1000., &[0, 10, 20]);
+        assert!((turns(&close, 250., RECENTER, turn_secs) - 0.4).abs() < 1e-9);
+        // The gap is longer than the recenter timeout, so the stick is back at center for the
+        // rest of it
+        let apart = samples(1000., &[0, 100]);
+        assert!((turns(&apart, 250., RECENTER, turn_secs) - 0.4).abs() < 1e-9);
+        // Either way counts
+        let left = samples(-1000., &[0, 100]);
+        assert_eq!(
+            turns(&left, 250., RECENTER, turn_secs),
+            turns(&apart, 250., RECENTER, turn_secs)
+        );
+        // Small motions turn more with more sensitivity
+        let slow = samples(0.1, &[0, 10, 20]);
+        assert!(turns(&slow, 10., RECENTER, turn_secs) < turns(&slow, 20., RECENTER, turn_secs));
+    }
+
+    #[test]
+    fn suggested_sensitivity_makes_one_turn() {
+        let motion = samples(0.5, &(0..100).map(|i| i * 10).collect::<Vec<_>>());
+        let suggested = suggest_sensitivity(&motion, RECENTER, 0.5).unwrap();
+        assert!((turns(&motion, suggested, RECENTER, 0.5) - 1.).abs() < 1e-6);
+        // A slower game needs more
+        assert!(suggest_sensitivity(&motion, RECENTER, 0.8).unwrap() > suggested);
+
+        // Even fully pushed the whole time this is less than a turn
+        let short = samples(0.5, &[0, 10]);
+        assert_eq!(suggest_sensitivity(&short, RECENTER, 0.5), None);
+    }
+}
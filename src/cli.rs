@@ -0,0 +1,75 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+/// Command line options
+#[derive(Default)]
+pub struct Args {
+    /// Debug option to skip `synchronize()` after sending events
+    pub no_sync: bool,
+    /// Config files to merge in order, instead of the default one
+    pub config_paths: Vec<PathBuf>,
+    /// Print the config after merging and exit
+    pub print_config: bool,
+    /// Name of the config profile to use
+    pub profile: Option<String>,
+    /// Print the key and controller names the config accepts and exit
+    pub list_keys: bool,
+    /// Existing event device to write to instead of creating one through uinput
+    pub device_path: Option<PathBuf>,
+    /// Start with the cursor visible, overriding `start_hidden` in the config
+    pub start_visible: bool,
+    /// Print mouse motion timing stats every second
+    pub timing: bool,
+    /// Print every event winit delivers before handling it
+    pub log_events: bool,
+    /// Run the guided sensitivity calibration instead of emulating a controller
+    pub calibrate: bool,
+    /// Feed inputs from a script file instead of capturing them
+    pub script: Option<PathBuf>,
+    /// Print controller events instead of sending them to a device
+    pub mock_device: bool,
+}
+
+impl Args {
+    pub fn parse() -> anyhow::Result<Self> {
+        let mut args = Self::default();
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--no-sync" => args.no_sync = true,
+                "--list-keys" => args.list_keys = true,
+                "--start-visible" => args.start_visible = true,
+                "--timing" => args.timing = true,
+                "--log-events" => args.log_events = true,
+                "--calibrate" => args.calibrate = true,
+                "--mock-device" => args.mock_device = true,
+                "--print-config" => args.print_config = true,
+                "--config" => {
+                    let path = iter.next().context("--config needs a path")?;
+                    args.config_paths.push(path.into());
+                }
+                "--script" => {
+                    let path = iter.next().context("--script needs a path")?;
+                    args.script = Some(path.into());
+                }
+                "--device-path" => {
+                    let path = iter.next().context("--device-path needs a path")?;
+                    args.device_path = Some(path.into());
+                }
+                "--profile" => {
+                    let profile = iter.next().context("--profile needs a profile name")?;
+                    args.profile = Some(profile);
+                }
+                _ => anyhow::bail!("Unknown argument: {arg}"),
+            }
+        }
+
+        anyhow::ensure!(
+            !(args.mock_device && args.device_path.is_some()),
+            "--mock-device and --device-path can't be used together"
+        );
+
+        Ok(args)
+    }
+}
@@ -0,0 +1,1641 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use uinput::event::{
+    absolute::Position,
+    controller::{Controller, GamePad},
+};
+use winit::keyboard::KeyCode;
+
+use crate::{
+    device,
+    names::{self, Axis, Button, Key},
+    AXIS_MAX, AXIS_MIN, MAX_DEADZONE, VERTICAL_SCALE_RANGE,
+};
+
+#[derive(Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How mouse motion is turned into stick values
+    pub stick: StickSettings,
+    /// How many times per second the current stick values get re-sent even if they haven't
+    /// changed. 0 disables it.
+    pub neutral_hold_hz: u32,
+    pub movement_magnitude: MovementMagnitude,
+    pub send_full: SendFullStrategy,
+    /// Write events to the device from a dedicated thread instead of the event loop
+    pub sender_thread: bool,
+    /// Real time (`SCHED_FIFO`) priority for the sender thread, from 1 to 99
+    pub sender_thread_priority: Option<i32>,
+    /// The CPU the sender thread is pinned to
+    pub sender_thread_cpu: Option<usize>,
+    /// While held, the right stick stays at its current value and mouse motion is ignored
+    pub camera_lock_key: Option<Key>,
+    pub camera_lock_motion: CameraLockMotion,
+    /// Recenters every axis, releases every button and clears all held state, to recover from
+    /// stuck input without restarting
+    pub reset_key: Option<Key>,
+    /// Switches to the next profile of `profile_cycle` while running
+    pub profile_cycle_key: Option<Key>,
+    pub profile_cycle: Vec<String>,
+    /// Presses of the profile cycle key this soon after the last switch are ignored
+    pub profile_switch_debounce_ms: u64,
+    /// Saves the settings in use, including what was changed while running, as a new profile
+    pub snapshot_key: Option<Key>,
+    pub wheel_nudge: Option<WheelNudge>,
+    pub speed_trigger: Option<SpeedTrigger>,
+    pub drift_flush: Option<DriftFlush>,
+    /// Recenter everything and release all buttons if no input arrives for this long. 0 disables it.
+    pub watchdog_ms: u64,
+    /// How often to check that xbanish is still running while the cursor is hidden, restarting
+    /// it if it died. 0 disables it.
+    pub xbanish_check_ms: u64,
+    /// Keys mapped to controller inputs, these take priority over the default mapping
+    pub bindings: HashMap<Key, Binding>,
+    pub layer: Option<Layer>,
+    /// What keys with no binding or default mapping do
+    pub unmapped_keys: UnmappedKeys,
+    /// Which half of a binding with both an axis and a button gets sent first
+    pub composite_order: CompositeOrder,
+    pub movement_ramp: MovementRamp,
+    /// How long the left stick stays where it is after the last movement key is released
+    pub movement_release_grace_ms: u64,
+    /// Releasing the last movement key centers the left stick right away, skipping the release
+    /// grace and the ramp down
+    pub instant_stop: bool,
+    /// Buttons stay pressed on the controller for at least this long, even for quicker taps
+    pub min_press_ms: u64,
+    pub direction_binds: Vec<DirectionBind>,
+    pub look_keys: LookKeys,
+    /// Hide the cursor (and start sending input) right away instead of waiting for the toggle key
+    pub start_hidden: bool,
+    /// Send nothing at all for this long after starting, then recenter everything
+    pub startup_delay_ms: u64,
+    /// Shell commands run when emulation starts and when it stops
+    pub startup_command: Option<String>,
+    pub shutdown_command: Option<String>,
+    /// What gets released when pausing with the toggle key
+    pub pause_release: PauseRelease,
+    /// Take the first mouse motion after unpausing as a starting point instead of sending it,
+    /// since it can carry movement from while paused
+    pub unpause_baseline: bool,
+    /// Snap the left stick to the closest diagonal, for isometric games
+    pub diagonal_lock: bool,
+    /// How far the left stick can go from center in any direction, so combined keys can't
+    /// push it past that
+    pub left_stick_max: Option<f64>,
+    pub invert_left_x: bool,
+    pub invert_left_y: bool,
+    /// Keys that grow and shrink `stick.deadzone` by `deadzone_step` while running
+    pub deadzone_up_key: Option<Key>,
+    pub deadzone_down_key: Option<Key>,
+    pub deadzone_step: f64,
+    /// While held, the deadzone is off so the smallest movements get through
+    pub deadzone_off_key: Option<Key>,
+    /// Keys that grow and shrink `stick.vertical_scale` by `vertical_scale_step` while running
+    pub vertical_scale_up_key: Option<Key>,
+    pub vertical_scale_down_key: Option<Key>,
+    pub vertical_scale_step: f64,
+    pub sensitivity_toggle: Option<SensitivityToggle>,
+    pub button_mode: Option<ButtonMode>,
+    /// The kind of controller to create
+    pub device: DeviceKind,
+    pub compat: Compat,
+    pub stick_scheme: StickScheme,
+    /// Axes driven by horizontal and vertical mouse motion
+    pub mouse_x_axis: Axis,
+    pub mouse_y_axis: Axis,
+}
+
+/// The tunables for the mouse driven stick, kept together so they're read from one place and a
+/// profile's `[stick]` table changes them all at once
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StickSettings {
+    /// Higher values turn the right stick further for the same mouse movement
+    pub sensitivity: f64,
+    /// Scales the mouse driven stick values after the response curve, unlike `sensitivity`
+    /// which scales the mouse motion going into it
+    pub gain_x: f64,
+    pub gain_y: f64,
+    /// How much more vertical mouse motion moves the stick than horizontal motion does
+    pub vertical_scale: f64,
+    /// How much of the previous right stick value is kept on each mouse motion, from 0 (off) to
+    /// just under 1. This also makes the stick ease back to center instead of snapping.
+    pub smoothing: f64,
+    pub smoothing_by_speed: Option<SmoothingBySpeed>,
+    /// A mouse motion at least this long against the previous one on the same axis skips the
+    /// smoothing for that axis, so reversals are crisp. 0 is off.
+    pub reversal_reset: f64,
+    /// Once the smoothed stick is within this distance of center it's pinned there
+    pub center_hold: f64,
+    /// Right stick values closer to center than this are sent as center
+    pub deadzone: f64,
+    /// Mouse motions push the right stick at least this far from center, to get past a game's
+    /// own deadzone. Recentering still sends exact center.
+    pub anti_deadzone: f64,
+    /// How long after the last mouse motion the stick goes back to center
+    pub recenter_ms: u64,
+    /// The first mouse motion this soon after the stick is recentered is taken as the new
+    /// starting point instead of being sent, since it was probably already under way
+    pub recenter_echo_ms: u64,
+    /// The stick isn't recentered when the mouse stops if it's closer to center than this but
+    /// not at it, so a slow constant pan keeps going. 0 always recenters.
+    pub recenter_hold_below: f64,
+    /// How long a stick held that way stays out before it's recentered anyway. 0 holds it until
+    /// the next mouse motion.
+    pub recenter_hold_ms: u64,
+    /// How fractional stick values from the mouse are turned into whole ones
+    pub rounding: Rounding,
+}
+
+impl StickSettings {
+    fn validate(&self, table: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.sensitivity > 0.,
+            "{table}.sensitivity must be more than 0"
+        );
+        anyhow::ensure!(
+            self.gain_x > 0. && self.gain_y > 0.,
+            "{table}.gain_x and {table}.gain_y must be more than 0"
+        );
+        anyhow::ensure!(
+            (0. ..1.).contains(&self.smoothing),
+            "{table}.smoothing must be at least 0 and less than 1"
+        );
+        if let Some(by_speed) = &self.smoothing_by_speed {
+            anyhow::ensure!(
+                0. <= by_speed.slow && by_speed.slow < by_speed.fast,
+                "{table}.smoothing_by_speed.slow must be at least 0 and less than fast"
+            );
+        }
+        anyhow::ensure!(
+            self.reversal_reset >= 0.,
+            "{table}.reversal_reset can't be negative"
+        );
+        anyhow::ensure!(
+            self.center_hold >= 0.,
+            "{table}.center_hold can't be negative"
+        );
+        anyhow::ensure!(
+            (0. ..=MAX_DEADZONE).contains(&self.deadzone),
+            "{table}.deadzone must be between 0 and {MAX_DEADZONE}"
+        );
+        anyhow::ensure!(
+            (0. ..AXIS_MAX as f64).contains(&self.anti_deadzone),
+            "{table}.anti_deadzone must be at least 0 and less than {AXIS_MAX}"
+        );
+        anyhow::ensure!(
+            self.recenter_hold_below >= 0.,
+            "{table}.recenter_hold_below can't be negative"
+        );
+        anyhow::ensure!(
+            self.recenter_hold_below == 0.
+                || self.recenter_hold_ms == 0
+                || self.recenter_hold_ms > self.recenter_ms,
+            "{table}.recenter_hold_ms must be longer than recenter_ms, or 0 to hold until the next motion"
+        );
+        anyhow::ensure!(
+            VERTICAL_SCALE_RANGE.contains(&self.vertical_scale),
+            "{table}.vertical_scale must be between {} and {}",
+            VERTICAL_SCALE_RANGE.start(),
+            VERTICAL_SCALE_RANGE.end()
+        );
+        anyhow::ensure!(
+            self.recenter_ms > 0,
+            "{table}.recenter_ms must be more than 0"
+        );
+        Ok(())
+    }
+
+    pub fn recenter_timeout(&self) -> Duration {
+        Duration::from_millis(self.recenter_ms)
+    }
+
+    pub fn recenter_hold(&self) -> Option<Duration> {
+        (self.recenter_hold_ms > 0).then(|| Duration::from_millis(self.recenter_hold_ms))
+    }
+
+    pub fn recenter_echo(&self) -> Option<Duration> {
+        (self.recenter_echo_ms > 0).then(|| Duration::from_millis(self.recenter_echo_ms))
+    }
+}
+
+impl Default for StickSettings {
+    fn default() -> Self {
+        Self {
+            sensitivity: 250.,
+            gain_x: 1.,
+            gain_y: 1.,
+            vertical_scale: 1.5,
+            smoothing: 0.,
+            smoothing_by_speed: None,
+            reversal_reset: 0.,
+            center_hold: 2.,
+            deadzone: 0.,
+            anti_deadzone: 0.,
+            recenter_ms: 20,
+            recenter_echo_ms: 2,
+            recenter_hold_below: 0.,
+            recenter_hold_ms: 500,
+            rounding: Rounding::default(),
+        }
+    }
+}
+
+/// Keys that push the mouse driven stick in a direction, added on top of the mouse
+#[derive(Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LookKeys {
+    /// How far each key pushes the stick
+    pub magnitude: f64,
+    pub keys: HashMap<Key, Direction>,
+}
+
+impl Default for LookKeys {
+    fn default() -> Self {
+        Self {
+            magnitude: 64.,
+            keys: HashMap::new(),
+        }
+    }
+}
+
+/// A key that flips between `stick.sensitivity` and another sensitivity, e.g. a quick one and a
+/// precise one
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SensitivityToggle {
+    pub key: Key,
+    pub alternate: f64,
+}
+
+/// A mouse button that switches the stick to other settings while it's held, e.g. a higher
+/// sensitivity for quick turns. The button isn't sent to the controller. The live tuning hotkeys
+/// and the sensitivity toggle still apply, relative to the mode's settings.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ButtonMode {
+    pub button: u32,
+    /// `[stick]` with these settings changed, filled in from it when loading
+    #[serde(default)]
+    pub stick: StickSettings,
+}
+
+/// Holds a button while the mouse moves in a direction, optionally only while a modifier is held
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DirectionBind {
+    pub direction: Direction,
+    pub modifier: Option<Key>,
+    pub button: Button,
+    /// The button gets pressed once a mouse motion goes further than this in `direction`
+    #[serde(default)]
+    pub arm: f64,
+    /// and released once it drops to this or below
+    #[serde(default)]
+    pub disarm: f64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+impl Direction {
+    /// The direction a mouse motion mostly goes in
+    pub fn of_motion((x, y): (f64, f64)) -> Option<Self> {
+        if x == 0. && y == 0. {
+            None
+        } else if x.abs() >= y.abs() {
+            Some(if x < 0. { Self::Left } else { Self::Right })
+        } else {
+            Some(if y < 0. { Self::Up } else { Self::Down })
+        }
+    }
+
+    /// How far a mouse motion goes in this direction
+    pub fn amount(self, (x, y): (f64, f64)) -> f64 {
+        match self {
+            Self::Left => -x,
+            Self::Right => x,
+            Self::Up => -y,
+            Self::Down => y,
+        }
+    }
+}
+
+/// Scales `smoothing` down as the mouse moves faster, so slow aiming is smooth while flicks stay
+/// responsive
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SmoothingBySpeed {
+    /// Motions up to this length (in mouse units per event) get the full smoothing
+    pub slow: f64,
+    /// and motions from this length on get none, fading linearly in between
+    pub fast: f64,
+}
+
+impl SmoothingBySpeed {
+    /// How much of `smoothing` to use for a motion of this length
+    pub fn scale(&self, speed: f64) -> f64 {
+        ((self.fast - speed) / (self.fast - self.slow)).clamp(0., 1.)
+    }
+}
+
+#[derive(Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum DeviceKind {
+    /// An Xbox 360 controller, which most games know the layout of
+    #[default]
+    Xbox360,
+    /// A plain gamepad with numbered buttons, for games that want a specific generic layout
+    Generic { buttons: u32, axes: u32 },
+}
+
+impl DeviceKind {
+    /// The `kind` names of every device this build can create
+    pub const NAMES: [&'static str; 2] = ["xbox360", "generic"];
+}
+
+/// Workarounds for games that read the controller differently from how it's meant to be read.
+/// Unlike the `invert_*` preferences these apply to everything sent, right before it goes out.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Compat {
+    /// Stick axes sent with their polarity flipped
+    pub inverted_axes: Vec<Axis>,
+}
+
+/// A second set of bindings that's switched to with a key
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layer {
+    pub key: Key,
+    #[serde(default)]
+    pub mode: LayerMode,
+    /// Held while `key` is, on top of switching the layer, whatever the mode
+    pub button: Option<Button>,
+    /// Used instead of the normal bindings while the layer is active
+    pub bindings: HashMap<Key, Binding>,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerMode {
+    /// Active while the key is held
+    #[default]
+    Hold,
+    /// Each press switches the layer on or off, like Caps Lock
+    Toggle,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+pub enum UnmappedKeys {
+    /// Drop them
+    #[default]
+    Ignore,
+    /// Print each press
+    Log,
+    /// Hold a controller button while any of them is held
+    Button { button: Button },
+}
+
+#[derive(Default, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PauseRelease {
+    /// Recenter the mouse driven right stick but keep keyboard inputs held
+    #[default]
+    Mouse,
+    /// Release everything
+    All,
+    /// Leave everything as it is
+    Nothing,
+}
+
+/// Which stick the mouse and the movement keys drive
+#[derive(Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StickScheme {
+    /// The movement keys drive the left stick and the mouse the right one
+    #[default]
+    Normal,
+    /// The mouse drives the left stick and the movement keys the right one
+    Swapped,
+}
+
+impl StickScheme {
+    /// Where something normally sent to `position` goes with this scheme
+    pub fn route(self, position: Position) -> Position {
+        match (self, position) {
+            (Self::Swapped, Position::X) => Position::RX,
+            (Self::Swapped, Position::Y) => Position::RY,
+            (Self::Swapped, Position::RX) => Position::X,
+            (Self::Swapped, Position::RY) => Position::Y,
+            (_, position) => position,
+        }
+    }
+}
+
+/// What happens to mouse motion while the camera lock is held
+#[derive(Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CameraLockMotion {
+    /// Drop it, so the mouse can be repositioned without turning
+    #[default]
+    Ignore,
+    /// Add it up and send it as one motion when the lock is let go
+    Accumulate,
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rounding {
+    /// Drop the fraction, which pulls small values towards 0
+    Truncate,
+    /// Round to the closest whole value, with halves going up (0.5 to 1, -0.5 to 0)
+    RoundHalfUp,
+    /// Round to the closest whole value, with halves going away from 0
+    #[default]
+    Nearest,
+}
+
+impl Rounding {
+    pub fn apply(self, value: f64) -> i32 {
+        match self {
+            Self::Truncate => value as i32,
+            Self::RoundHalfUp => (value + 0.5).floor() as i32,
+            Self::Nearest => value.round() as i32,
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompositeOrder {
+    #[default]
+    ButtonsFirst,
+    AxesFirst,
+}
+
+/// Eases the left stick in and out instead of snapping when movement keys are pressed and released
+#[derive(Default, Clone, Copy, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MovementRamp {
+    /// How long it takes to go from center to full deflection
+    pub attack_ms: u64,
+    /// How long it takes to go from full deflection back to center
+    pub release_ms: u64,
+}
+
+impl MovementRamp {
+    pub fn enabled(&self) -> bool {
+        self.attack_ms > 0 || self.release_ms > 0
+    }
+}
+
+/// What a key does when bound in the config. It can push a left stick axis, press a button, or both
+/// at once (e.g. for games with a digital sprint button next to analog movement).
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Binding {
+    pub axis: Option<Axis>,
+    /// How far to push `axis`
+    #[serde(default)]
+    pub value: i32,
+    pub button: Option<Button>,
+    /// More buttons pressed and released together with `button`, e.g. Start and Select for
+    /// games that want both at once
+    #[serde(default)]
+    pub buttons: Vec<Button>,
+    /// Each press sends the next of these buttons instead of the same ones every time, releasing
+    /// it with the key
+    #[serde(default)]
+    pub cycle: Vec<Button>,
+    #[serde(default)]
+    pub cycle_end: CycleEnd,
+    /// Each press switches the binding on or off instead of it being held
+    #[serde(default)]
+    pub toggle: bool,
+    /// Presses and releases the buttons this many times per second while engaged. 0 is off.
+    #[serde(default)]
+    pub turbo_hz: f64,
+    /// A tap presses the buttons once, while holding for longer than this starts repeating them
+    /// `repeat_hz` times per second, like menus do with held D-pad directions
+    #[serde(default)]
+    pub repeat_delay_ms: u64,
+    #[serde(default)]
+    pub repeat_hz: f64,
+    /// How far to pull TL2/TR2, from just above 0 to 1 (fully), on devices with analog triggers
+    pub pressure: Option<f64>,
+    /// Pulls both triggers at once, each to its own level
+    pub triggers: Option<TriggerLevels>,
+}
+
+/// How far a binding pulls each trigger, from just above 0 to 1 (fully)
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TriggerLevels {
+    pub left: f64,
+    pub right: f64,
+}
+
+/// What a cycling binding does after its last button
+#[derive(Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CycleEnd {
+    /// Start over from the first button
+    #[default]
+    Wrap,
+    /// Keep sending the last button
+    Stop,
+}
+
+impl Binding {
+    /// Every button the binding can press, including all of `cycle` and the triggers of
+    /// `triggers`
+    pub fn all_buttons(&self) -> impl Iterator<Item = Button> + '_ {
+        let triggers = self.triggers.is_some().then_some([
+            Button(Controller::GamePad(GamePad::TL2)),
+            Button(Controller::GamePad(GamePad::TR2)),
+        ]);
+        self.button
+            .iter()
+            .chain(&self.buttons)
+            .chain(&self.cycle)
+            .copied()
+            .chain(triggers.into_iter().flatten())
+    }
+
+    /// How far pressing `button` pulls its trigger, if it's a trigger the binding has a level for
+    pub fn pressure_for(&self, button: Button) -> Option<f64> {
+        match (self.triggers, device::trigger_axis(button.0)) {
+            (Some(levels), Some(Position::Z)) => Some(levels.left),
+            (Some(levels), Some(_)) => Some(levels.right),
+            _ => self.pressure,
+        }
+    }
+
+    /// Which button of `cycle` comes after the one at `index`
+    pub fn next_in_cycle(&self, index: usize) -> usize {
+        match self.cycle_end {
+            CycleEnd::Wrap => (index + 1) % self.cycle.len().max(1),
+            CycleEnd::Stop => (index + 1).min(self.cycle.len().saturating_sub(1)),
+        }
+    }
+
+    /// How long until the buttons first get released while engaged, and then how long they stay
+    /// released and pressed in each cycle, for turbo and repeat bindings
+    pub fn pulse(&self) -> Option<(Duration, Duration)> {
+        let half_period = |hz: f64| Duration::from_secs_f64(0.5 / hz);
+        if self.turbo_hz > 0. {
+            let half_period = half_period(self.turbo_hz);
+            Some((half_period, half_period))
+        } else if self.repeat_hz > 0. {
+            let delay = Duration::from_millis(self.repeat_delay_ms);
+            Some((delay, half_period(self.repeat_hz)))
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            stick: StickSettings::default(),
+            neutral_hold_hz: 0,
+            movement_magnitude: MovementMagnitude::default(),
+            send_full: SendFullStrategy::default(),
+            sender_thread: false,
+            sender_thread_priority: None,
+            sender_thread_cpu: None,
+            camera_lock_key: None,
+            camera_lock_motion: CameraLockMotion::default(),
+            reset_key: None,
+            profile_cycle_key: None,
+            profile_cycle: Vec::new(),
+            profile_switch_debounce_ms: 300,
+            snapshot_key: None,
+            wheel_nudge: None,
+            speed_trigger: None,
+            drift_flush: None,
+            watchdog_ms: 60_000,
+            xbanish_check_ms: 1000,
+            bindings: HashMap::new(),
+            layer: None,
+            unmapped_keys: UnmappedKeys::default(),
+            composite_order: CompositeOrder::default(),
+            movement_ramp: MovementRamp::default(),
+            movement_release_grace_ms: 0,
+            instant_stop: false,
+            min_press_ms: 0,
+            direction_binds: Vec::new(),
+            look_keys: LookKeys::default(),
+            start_hidden: true,
+            startup_delay_ms: 0,
+            startup_command: None,
+            shutdown_command: None,
+            pause_release: PauseRelease::default(),
+            unpause_baseline: true,
+            diagonal_lock: false,
+            left_stick_max: None,
+            invert_left_x: false,
+            invert_left_y: false,
+            deadzone_up_key: None,
+            deadzone_down_key: None,
+            deadzone_step: 1.,
+            deadzone_off_key: None,
+            vertical_scale_up_key: None,
+            vertical_scale_down_key: None,
+            vertical_scale_step: 0.1,
+            sensitivity_toggle: None,
+            button_mode: None,
+            device: DeviceKind::default(),
+            compat: Compat::default(),
+            stick_scheme: StickScheme::default(),
+            mouse_x_axis: Axis(Position::RX),
+            mouse_y_axis: Axis(Position::RY),
+        }
+    }
+}
+
+/// Nudges a left stick axis when scrolling the mouse wheel
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WheelNudge {
+    pub axis: Axis,
+    /// How much each notch pushes the stick, negative to flip the direction
+    pub per_notch: f64,
+    /// How long it takes a full deflection to decay back to center
+    pub decay_ms: u64,
+}
+
+/// Pulls a trigger further the faster the mouse moves
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpeedTrigger {
+    pub axis: Axis,
+    /// Mouse motions this long (in mouse units per event) or longer pull the trigger fully
+    pub full_speed: f64,
+    /// How long a fully pulled trigger takes to let go once the mouse stops
+    pub decay_ms: u64,
+}
+
+/// Sends exact center a few more times once the mouse has left the stick centered for a while,
+/// for games that latch a tiny leftover value and slowly drift
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DriftFlush {
+    /// How long the stick has to stay centered first
+    pub idle_ms: u64,
+    /// How many centered reports to send
+    pub count: u32,
+}
+
+impl DriftFlush {
+    pub fn idle(&self) -> Duration {
+        Duration::from_millis(self.idle_ms)
+    }
+}
+
+/// What to do with an event when the device buffer is full
+#[derive(Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SendFullStrategy {
+    /// Drop the event and log it
+    #[default]
+    Log,
+    /// Try again a few times after a short sleep, then drop it
+    Retry,
+    /// Hold it back and send it once there's room, in the order things were sent. Axes only
+    /// keep their latest value, buttons keep every press and release.
+    Coalesce,
+}
+
+/// How far the left stick gets pushed by each movement key
+#[derive(Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MovementMagnitude {
+    pub forward: i32,
+    pub backward: i32,
+    pub left: i32,
+    pub right: i32,
+}
+
+impl Default for MovementMagnitude {
+    fn default() -> Self {
+        Self {
+            forward: -AXIS_MIN,
+            backward: AXIS_MAX,
+            left: -AXIS_MIN,
+            right: AXIS_MAX,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config files in `paths`, merged in order, or the default config file if none
+    /// are given
+    pub fn load(paths: &[PathBuf], profile: Option<&str>) -> anyhow::Result<Self> {
+        // The default file is optional but files asked for by name aren't
+        let (paths, required) = if paths.is_empty() {
+            match config_path() {
+                Some(path) => (vec![path], false),
+                None if profile.is_some() => anyhow::bail!("No config file to load profiles from"),
+                None => return Ok(Self::default()),
+            }
+        } else {
+            (paths.to_vec(), true)
+        };
+
+        let mut table = toml::Table::new();
+        for path in &paths {
+            let text = match std::fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound && !required => {
+                    String::new()
+                }
+                Err(err) => {
+                    return Err(err).with_context(|| format!("Failed to read {}", path.display()))
+                }
+            };
+
+            let file_table: toml::Table = text
+                .parse()
+                .with_context(|| format!("Failed to parse {}", path.display()))?;
+            merge_tables(&mut table, file_table);
+        }
+
+        let source = match paths.as_slice() {
+            [path] => path.display().to_string(),
+            _ => "the merged config files".to_string(),
+        };
+        Self::from_table(table, profile, &source)
+    }
+
+    /// Builds the config from the merged config files, with `profile` applied on top. `source`
+    /// says where they came from in errors.
+    pub fn from_table(
+        mut table: toml::Table,
+        profile: Option<&str>,
+        source: &str,
+    ) -> anyhow::Result<Self> {
+        let profiles = table.remove("profiles");
+        if let Some(profile) = profile {
+            let profiles = match profiles {
+                Some(toml::Value::Table(profiles)) => profiles,
+                Some(_) => anyhow::bail!("`profiles` in {source} must be a table"),
+                None => toml::Table::new(),
+            };
+            apply_profile(&mut table, profiles, profile)
+                .with_context(|| format!("Failed to load profile from {source}"))?;
+        }
+
+        // The mode only lists what it changes, everything else comes from [stick]
+        let mut stick = match table.get("stick") {
+            Some(toml::Value::Table(stick)) => stick.clone(),
+            _ => toml::Table::new(),
+        };
+        if let Some(toml::Value::Table(mode)) = table.get_mut("button_mode") {
+            match mode.remove("stick") {
+                Some(toml::Value::Table(overrides)) => merge_tables(&mut stick, overrides),
+                Some(_) => anyhow::bail!("`button_mode.stick` in {source} must be a table"),
+                None => {}
+            }
+            mode.insert("stick".to_string(), stick.into());
+        }
+
+        let kind = table
+            .get("device")
+            .and_then(|device| device.get("kind"))
+            .and_then(toml::Value::as_str);
+        if let Some(kind) = kind {
+            anyhow::ensure!(
+                DeviceKind::NAMES.contains(&kind),
+                "Unknown device kind `{kind}` in {source}, the supported kinds are {}",
+                DeviceKind::NAMES.join(", ")
+            );
+        }
+
+        let config: Self = table
+            .try_into()
+            .with_context(|| format!("Failed to parse {source}"))?;
+        config
+            .validate()
+            .with_context(|| format!("Invalid config in {source}"))?;
+        Ok(config)
+    }
+
+    /// Loads every profile of `profile_cycle` from the same files, for switching between them
+    /// while running
+    pub fn load_profile_cycle(&self, paths: &[PathBuf]) -> anyhow::Result<Vec<(String, Self)>> {
+        let mut profiles = Vec::new();
+        for name in &self.profile_cycle {
+            let config = Self::load(paths, Some(name))
+                .with_context(|| format!("Failed to load profile `{name}` of profile_cycle"))?;
+            // The controller is only created once
+            anyhow::ensure!(
+                config.device == self.device,
+                "Profile `{name}` of profile_cycle has a different [device]"
+            );
+            profiles.push((name.clone(), config));
+        }
+        Ok(profiles)
+    }
+
+    pub fn profile_switch_debounce(&self) -> Duration {
+        Duration::from_millis(self.profile_switch_debounce_ms)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let magnitude = &self.movement_magnitude;
+        for (name, value, max) in [
+            ("forward", magnitude.forward, -AXIS_MIN),
+            ("backward", magnitude.backward, AXIS_MAX),
+            ("left", magnitude.left, -AXIS_MIN),
+            ("right", magnitude.right, AXIS_MAX),
+        ] {
+            anyhow::ensure!(
+                (0..=max).contains(&value),
+                "movement_magnitude.{name} must be between 0 and {max}, got {value}"
+            );
+        }
+
+        anyhow::ensure!(
+            self.profile_cycle_key.is_none() || !self.profile_cycle.is_empty(),
+            "profile_cycle_key needs profiles to switch between in profile_cycle"
+        );
+
+        self.stick.validate("stick")?;
+        if let Some(toggle) = &self.sensitivity_toggle {
+            anyhow::ensure!(
+                toggle.alternate > 0.,
+                "sensitivity_toggle.alternate must be more than 0"
+            );
+        }
+        if let Some(mode) = &self.button_mode {
+            mode.stick.validate("button_mode.stick")?;
+        }
+
+        if let Some(max) = self.left_stick_max {
+            anyhow::ensure!(
+                max > 0. && max <= AXIS_MAX as f64,
+                "left_stick_max must be more than 0 and at most {AXIS_MAX}"
+            );
+        }
+
+        // Errors only reach the sender thread, which can't hand them back to be retried
+        anyhow::ensure!(
+            !self.sender_thread || self.send_full == SendFullStrategy::default(),
+            "send_full can't be used with sender_thread, which only logs errors"
+        );
+        anyhow::ensure!(
+            self.sender_thread
+                || (self.sender_thread_priority.is_none() && self.sender_thread_cpu.is_none()),
+            "sender_thread_priority and sender_thread_cpu need sender_thread on"
+        );
+        if let Some(priority) = self.sender_thread_priority {
+            anyhow::ensure!(
+                (1..=99).contains(&priority),
+                "sender_thread_priority must be between 1 and 99"
+            );
+        }
+
+        if let DeviceKind::Generic { buttons, axes } = self.device {
+            anyhow::ensure!(
+                (1..=device::MAX_GENERIC_BUTTONS).contains(&buttons),
+                "device.buttons must be between 1 and {}",
+                device::MAX_GENERIC_BUTTONS
+            );
+            anyhow::ensure!(
+                axes as usize <= device::GENERIC_AXES.len(),
+                "device.axes can be at most {}",
+                device::GENERIC_AXES.len()
+            );
+        }
+
+        for axis in &self.compat.inverted_axes {
+            anyhow::ensure!(
+                device::STICK_AXES.contains(&axis.0),
+                "compat.inverted_axes can only have stick axes (X, Y, RX or RY)"
+            );
+        }
+
+        for (name, axis) in [
+            ("mouse_x_axis", self.mouse_x_axis),
+            ("mouse_y_axis", self.mouse_y_axis),
+        ] {
+            anyhow::ensure!(
+                self.device.declares(axis.0),
+                "{name} must be an axis the controller has"
+            );
+        }
+        if self.stick_scheme == StickScheme::Swapped {
+            anyhow::ensure!(
+                self.mouse_x_axis.0 == Position::RX && self.mouse_y_axis.0 == Position::RY,
+                "stick_scheme = \"swapped\" needs mouse_x_axis and mouse_y_axis left at RX and RY"
+            );
+        }
+        anyhow::ensure!(
+            self.mouse_x_axis != self.mouse_y_axis,
+            "mouse_x_axis and mouse_y_axis must be different axes"
+        );
+
+        if let Some(speed_trigger) = &self.speed_trigger {
+            anyhow::ensure!(
+                device::is_trigger(speed_trigger.axis.0)
+                    && self.device.declares(speed_trigger.axis.0),
+                "speed_trigger.axis must be a trigger axis the controller has (Z or RZ)"
+            );
+            anyhow::ensure!(
+                ![self.mouse_x_axis, self.mouse_y_axis].contains(&speed_trigger.axis),
+                "speed_trigger.axis is already driven by the mouse"
+            );
+            anyhow::ensure!(
+                speed_trigger.full_speed > 0.,
+                "speed_trigger.full_speed must be more than 0"
+            );
+        }
+
+        if let Some(drift_flush) = &self.drift_flush {
+            anyhow::ensure!(
+                drift_flush.idle_ms > 0,
+                "drift_flush.idle_ms must be more than 0"
+            );
+            anyhow::ensure!(
+                drift_flush.count > 0,
+                "drift_flush.count must be more than 0"
+            );
+        }
+
+        if let UnmappedKeys::Button { button } = self.unmapped_keys {
+            anyhow::ensure!(
+                self.device.has_button(button.0),
+                "unmapped_keys.button {} isn't on the controller",
+                names::button_name(button.0)
+            );
+        }
+
+        if let Some(button) = self.layer.as_ref().and_then(|layer| layer.button) {
+            anyhow::ensure!(
+                self.device.has_button(button.0),
+                "layer.button {} isn't on the controller",
+                names::button_name(button.0)
+            );
+        }
+
+        for bind in &self.direction_binds {
+            anyhow::ensure!(
+                self.device.has_button(bind.button.0),
+                "direction_binds button {} isn't on the controller",
+                names::button_name(bind.button.0)
+            );
+            anyhow::ensure!(
+                0. <= bind.disarm && bind.disarm <= bind.arm,
+                "direction_binds disarm must be between 0 and arm"
+            );
+        }
+
+        anyhow::ensure!(
+            self.look_keys.magnitude > 0. && self.look_keys.magnitude <= AXIS_MAX as f64,
+            "look_keys.magnitude must be more than 0 and at most {AXIS_MAX}"
+        );
+        let layer_bindings = self.layer.iter().flat_map(|layer| &layer.bindings);
+        for key in self
+            .bindings
+            .keys()
+            .chain(layer_bindings.map(|(key, _)| key))
+        {
+            anyhow::ensure!(
+                !self.look_keys.keys.contains_key(key),
+                "{} is both a look key and bound in [bindings]",
+                names::key_name(key.0)
+            );
+        }
+
+        if let Some(nudge) = &self.wheel_nudge {
+            anyhow::ensure!(
+                matches!(nudge.axis.0, Position::X | Position::Y),
+                "wheel_nudge.axis must be a left stick axis (X or Y)"
+            );
+        }
+
+        let layer_bindings = self.layer.iter().flat_map(|layer| &layer.bindings);
+        for (key, binding) in self.bindings.iter().chain(layer_bindings) {
+            let key_name = names::key_name(key.0);
+            anyhow::ensure!(
+                binding.axis.is_some() || binding.all_buttons().next().is_some(),
+                "Binding for {key_name} needs an axis or a button"
+            );
+            anyhow::ensure!(
+                binding.cycle.is_empty()
+                    || (binding.button.is_none() && binding.buttons.is_empty()),
+                "Binding for {key_name} can't have both cycle and button(s)"
+            );
+            if let Some(levels) = binding.triggers {
+                anyhow::ensure!(
+                    binding.pressure.is_none() && binding.cycle.is_empty(),
+                    "Binding for {key_name} can't have triggers with pressure or cycle"
+                );
+                anyhow::ensure!(
+                    [levels.left, levels.right]
+                        .iter()
+                        .all(|level| *level > 0. && *level <= 1.),
+                    "Binding for {key_name} needs trigger levels above 0 and up to 1"
+                );
+            }
+            if let Some(pressure) = binding.pressure {
+                anyhow::ensure!(
+                    pressure > 0. && pressure <= 1.,
+                    "Binding for {key_name} needs a pressure above 0 and up to 1"
+                );
+                anyhow::ensure!(
+                    binding
+                        .all_buttons()
+                        .any(|button| device::trigger_axis(button.0).is_some()),
+                    "Binding for {key_name} has a pressure but no trigger button (TL2 or TR2)"
+                );
+            }
+            for button in binding.all_buttons() {
+                anyhow::ensure!(
+                    self.device.has_button(button.0),
+                    "Binding for {key_name} uses {} which isn't on the controller",
+                    names::button_name(button.0)
+                );
+            }
+            anyhow::ensure!(
+                binding.turbo_hz >= 0.,
+                "Binding for {key_name} can't have a negative turbo_hz"
+            );
+            anyhow::ensure!(
+                binding.repeat_hz >= 0.,
+                "Binding for {key_name} can't have a negative repeat_hz"
+            );
+            anyhow::ensure!(
+                binding.repeat_delay_ms == 0 || binding.repeat_hz > 0.,
+                "Binding for {key_name} has a repeat_delay_ms but no repeat_hz"
+            );
+            anyhow::ensure!(
+                !(binding.turbo_hz > 0. && binding.repeat_hz > 0.),
+                "Binding for {key_name} can't have both turbo_hz and repeat_hz"
+            );
+            if let Some(axis) = binding.axis {
+                anyhow::ensure!(
+                    matches!(axis.0, Position::X | Position::Y),
+                    "Binding for {key_name} must use a left stick axis (X or Y)"
+                );
+                anyhow::ensure!(
+                    (AXIS_MIN..=AXIS_MAX).contains(&binding.value),
+                    "Binding for {key_name} has a value outside of {AXIS_MIN} to {AXIS_MAX}"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Problems that don't stop the config from loading but probably aren't what was meant
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        if self.instant_stop {
+            if self.movement_release_grace_ms > 0 {
+                warnings.push(
+                    "instant_stop is on, so movement_release_grace_ms does nothing".to_string(),
+                );
+            }
+            if self.movement_ramp.release_ms > 0 {
+                warnings.push(
+                    "instant_stop is on, so movement_ramp.release_ms does nothing when the last movement key is released".to_string(),
+                );
+            }
+        }
+
+        let layer_bindings = self.layer.iter().flat_map(|layer| &layer.bindings);
+        for (key, binding) in self.bindings.iter().chain(layer_bindings) {
+            if binding.toggle && binding.turbo_hz > 0. {
+                warnings.push(format!(
+                    "Binding for {} has both toggle and turbo_hz, each press switches turbo on or off",
+                    names::key_name(key.0)
+                ));
+            }
+        }
+
+        let layer_bindings = self.layer.as_ref().map(|layer| &layer.bindings);
+        for layer_bindings in [None].into_iter().chain(layer_bindings.map(Some)) {
+            let pushes = self.axis_pushes(layer_bindings);
+            let mut grouped: Vec<(Position, bool, Vec<KeyCode>)> = Vec::new();
+            for (key, position, value) in pushes {
+                let positive = value > 0;
+                match grouped
+                    .iter_mut()
+                    .find(|(p, s, _)| *p == position && *s == positive)
+                {
+                    Some((_, _, keys)) => keys.push(key),
+                    None => grouped.push((position, positive, vec![key])),
+                }
+            }
+
+            for (position, _, keys) in grouped {
+                // Groups without a layer key were already warned about for the base bindings
+                let in_layer = layer_bindings.is_none_or(|bindings| {
+                    keys.iter().any(|key| bindings.contains_key(&Key(*key)))
+                });
+                if keys.len() < 2 || !in_layer {
+                    continue;
+                }
+
+                let mut key_names: Vec<_> = keys.iter().map(|key| names::key_name(*key)).collect();
+                key_names.sort();
+                warnings.push(format!(
+                    "{} push {} the same way{}, holding them together doesn't push it any further",
+                    key_names.join(", "),
+                    names::axis_name(position),
+                    if layer_bindings.is_some() {
+                        " in the layer"
+                    } else {
+                        ""
+                    },
+                ));
+            }
+        }
+
+        warnings
+    }
+
+    /// Every key that pushes a left stick axis and how far, with the layer's bindings if given
+    fn axis_pushes(
+        &self,
+        layer_bindings: Option<&HashMap<Key, Binding>>,
+    ) -> Vec<(KeyCode, Position, i32)> {
+        let default_keys = [KeyCode::KeyW, KeyCode::KeyA, KeyCode::KeyS, KeyCode::KeyD];
+        let mut keys: Vec<KeyCode> = default_keys.into_iter().collect();
+        keys.extend(self.bindings.keys().map(|key| key.0));
+        keys.extend(
+            layer_bindings
+                .into_iter()
+                .flat_map(|b| b.keys().map(|key| key.0)),
+        );
+        keys.sort_by_key(|key| names::key_name(*key));
+        keys.dedup();
+
+        keys.into_iter()
+            .filter_map(|key| {
+                let binding = layer_bindings
+                    .and_then(|bindings| bindings.get(&Key(key)))
+                    .or_else(|| self.bindings.get(&Key(key)));
+                let push = match binding {
+                    Some(binding) => binding.axis.map(|axis| (axis.0, binding.value)),
+                    None => crate::key_to_position(key, &self.movement_magnitude),
+                };
+                push.filter(|(_, value)| *value != 0)
+                    .map(|(position, value)| (key, position, value))
+            })
+            .collect()
+    }
+
+    pub fn neutral_hold_period(&self) -> Option<Duration> {
+        (self.neutral_hold_hz > 0).then(|| Duration::from_secs(1) / self.neutral_hold_hz)
+    }
+
+    pub fn watchdog_timeout(&self) -> Option<Duration> {
+        (self.watchdog_ms > 0).then(|| Duration::from_millis(self.watchdog_ms))
+    }
+
+    pub fn xbanish_check_period(&self) -> Option<Duration> {
+        (self.xbanish_check_ms > 0).then(|| Duration::from_millis(self.xbanish_check_ms))
+    }
+
+    pub fn startup_delay(&self) -> Option<Duration> {
+        (self.startup_delay_ms > 0).then(|| Duration::from_millis(self.startup_delay_ms))
+    }
+
+    pub fn min_press(&self) -> Option<Duration> {
+        (self.min_press_ms > 0).then(|| Duration::from_millis(self.min_press_ms))
+    }
+
+    pub fn movement_release_grace(&self) -> Option<Duration> {
+        (self.movement_release_grace_ms > 0)
+            .then(|| Duration::from_millis(self.movement_release_grace_ms))
+    }
+}
+
+/// Merges the profile called `name` and everything it inherits from on top of `table`
+fn apply_profile(
+    table: &mut toml::Table,
+    mut profiles: toml::Table,
+    name: &str,
+) -> anyhow::Result<()> {
+    // Walk from the profile up to the root of its inheritance chain
+    let mut chain: Vec<(String, toml::Table)> = Vec::new();
+    let mut next = Some(name.to_string());
+    while let Some(name) = next {
+        if chain.iter().any(|(parent, _)| *parent == name) {
+            let names: Vec<_> = chain.iter().map(|(name, _)| name.as_str()).collect();
+            anyhow::bail!(
+                "Profile inheritance cycle: {} -> {name}",
+                names.join(" -> ")
+            );
+        }
+
+        let mut profile = match profiles.remove(&name) {
+            Some(toml::Value::Table(profile)) => profile,
+            Some(_) => anyhow::bail!("Profile `{name}` must be a table"),
+            None => match chain.last() {
+                Some((child, _)) => {
+                    anyhow::bail!("Profile `{child}` inherits from `{name}` which doesn't exist")
+                }
+                None => anyhow::bail!("No profile named `{name}`"),
+            },
+        };
+
+        next = match profile.remove("inherits") {
+            Some(toml::Value::String(parent)) => Some(parent),
+            Some(_) => anyhow::bail!("`inherits` in profile `{name}` must be a string"),
+            None => None,
+        };
+        chain.push((name, profile));
+    }
+
+    for (_, profile) in chain.into_iter().rev() {
+        merge_tables(table, profile);
+    }
+
+    Ok(())
+}
+
+/// Recursively merges `overlay` into `base`, with values from `overlay` taking priority
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_tables(base, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Adds `profile` to the end of the config file at `path` under the first `snapshot-N` name
+/// that isn't taken, leaving the rest of the file as it is. Returns the name it was saved as.
+pub fn append_profile(path: &Path, profile: toml::Table) -> anyhow::Result<String> {
+    let (text, existed) = match std::fs::read_to_string(path) {
+        Ok(text) => (text, true),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (String::new(), false),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let table: toml::Table = text
+        .parse()
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let taken = |name: &str| {
+        table
+            .get("profiles")
+            .and_then(|profiles| profiles.get(name))
+            .is_some()
+    };
+    let name = (1..)
+        .map(|n| format!("snapshot-{n}"))
+        .find(|name| !taken(name))
+        .expect("there's always a free name");
+
+    let mut profiles = toml::Table::new();
+    profiles.insert(name.clone(), profile.into());
+    let mut wrapper = toml::Table::new();
+    wrapper.insert("profiles".to_string(), profiles.into());
+    let profile_text = toml::to_string(&wrapper)?;
+
+    let mut new_text = text.clone();
+    if !new_text.is_empty() {
+        if !new_text.ends_with('\n') {
+            new_text.push('\n');
+        }
+        new_text.push('\n');
+    }
+    new_text.push_str(&profile_text);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, new_text)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    // Load it back the same way as `--profile` would, putting the file back if that fails
+    if let Err(err) = Config::load(&[path.to_path_buf()], Some(&name)) {
+        let restored = if existed {
+            std::fs::write(path, text)
+        } else {
+            std::fs::remove_file(path)
+        };
+        if let Err(restore_err) = restored {
+            eprintln!(
+                "Failed to undo the change to {}: {restore_err}",
+                path.display()
+            );
+        }
+        return Err(err.context("The profile doesn't load back"));
+    }
+    Ok(name)
+}
+
+/// Sets `key = value` in a config file, at the top level or in `table`. The line is changed in
+/// place so the rest of the file (and its comments) stays as it is, unless the file is laid out
+/// in a way that doesn't read back the same after that (like a dotted `stick.sensitivity` key),
+/// in which case the whole file is written out again. Nothing is written if the config doesn't
+/// load with the new value.
+pub fn set_value(
+    path: &Path,
+    table: Option<&str>,
+    key: &str,
+    value: toml::Value,
+) -> anyhow::Result<()> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let mut expected: toml::Table = text
+        .parse()
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let target = match table {
+        None => &mut expected,
+        Some(table) => match expected
+            .entry(table)
+            .or_insert_with(|| toml::Table::new().into())
+        {
+            toml::Value::Table(target) => target,
+            _ => anyhow::bail!("`{table}` in {} must be a table", path.display()),
+        },
+    };
+    target.insert(key.to_string(), value.clone());
+    Config::from_table(expected.clone(), None, &path.display().to_string())?;
+
+    let edited = set_line(&text, table, key, &value.to_string());
+    let new_text = if edited
+        .parse::<toml::Table>()
+        .is_ok_and(|edited| edited == expected)
+    {
+        edited
+    } else {
+        toml::to_string(&expected)?
+    };
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, new_text).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Replaces or adds the `key = ...` line in `table`, going only by the lines of the file
+fn set_line(text: &str, table: Option<&str>, key: &str, value: &str) -> String {
+    let mut lines: Vec<String> = text.lines().map(String::from).collect();
+    let new_line = format!("{key} = {value}");
+    let section_start = match table {
+        None => Some(0),
+        Some(table) => {
+            let header = format!("[{table}]");
+            lines
+                .iter()
+                .position(|line| line.split('#').next().unwrap_or_default().trim() == header)
+                .map(|i| i + 1)
+        }
+    };
+    match (section_start, table) {
+        (Some(start), _) => {
+            let end = lines[start..]
+                .iter()
+                .position(|line| line.trim_start().starts_with('['))
+                .map_or(lines.len(), |i| start + i);
+            let existing = lines[start..end].iter().position(|line| {
+                line.split_once('=')
+                    .is_some_and(|(line_key, _)| line_key.trim() == key)
+            });
+            match existing {
+                Some(i) => lines[start + i] = new_line,
+                None => lines.insert(start, new_line),
+            }
+        }
+        (None, table) => {
+            if lines.last().is_some_and(|line| !line.is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push(format!("[{}]", table.unwrap_or_default()));
+            lines.push(new_line);
+        }
+    }
+
+    lines.join("\n") + "\n"
+}
+
+/// The config file that changes made while running get written to: the last one given with
+/// `--config` since it takes priority, or the default one
+pub fn save_path(config_paths: &[PathBuf]) -> Option<PathBuf> {
+    config_paths.last().cloned().or_else(config_path)
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("mouse-con").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(text: &str, profile: Option<&str>) -> anyhow::Result<Config> {
+        Config::from_table(text.parse().unwrap(), profile, "test.toml")
+    }
+
+    fn load_error(text: &str, profile: Option<&str>) -> String {
+        match load(text, profile) {
+            Ok(_) => panic!("the config loaded"),
+            Err(err) => format!("{err:#}"),
+        }
+    }
+
+    #[test]
+    fn profile_inherits_through_two_levels() {
+        let config = load(
+            r#"
+            neutral_hold_hz = 10
+            watchdog_ms = 100
+
+            [profiles.base]
+            neutral_hold_hz = 20
+            watchdog_ms = 200
+            min_press_ms = 5
+
+            [profiles.middle]
+            inherits = "base"
+            watchdog_ms = 300
+            min_press_ms = 10
+
+            [profiles.top]
+            inherits = "middle"
+            min_press_ms = 15
+            "#,
+            Some("top"),
+        )
+        .unwrap();
+
+        // Each level only overrides what it lists
+        assert_eq!(config.neutral_hold_hz, 20);
+        assert_eq!(config.watchdog_ms, 300);
+        assert_eq!(config.min_press_ms, 15);
+    }
+
+    #[test]
+    fn profile_inheritance_cycle_is_an_error() {
+        let err = load_error(
+            r#"
+            [profiles.a]
+            inherits = "b"
+
+            [profiles.b]
+            inherits = "c"
+
+            [profiles.c]
+            inherits = "a"
+            "#,
+            Some("a"),
+        );
+
+        assert!(
+            err.contains("Profile inheritance cycle: a -> b -> c -> a"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn profile_with_missing_parent_is_an_error() {
+        let err = load_error(
+            r#"
+            [profiles.a]
+            inherits = "gone"
+            "#,
+            Some("a"),
+        );
+
+        assert!(
+            err.contains("Profile `a` inherits from `gone` which doesn't exist"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn unknown_device_kind_lists_the_supported_ones() {
+        assert_eq!(
+            load_error("[device]\nkind = \"ps4\"", None),
+            "Unknown device kind `ps4` in test.toml, the supported kinds are xbox360, generic"
+        );
+    }
+
+    #[test]
+    fn stick_scheme_routes() {
+        use Position::*;
+        #[rustfmt::skip]
+        let cases = [
+            (StickScheme::Normal, [(X, X), (Y, Y), (RX, RX), (RY, RY), (Z, Z), (RZ, RZ)]),
+            (StickScheme::Swapped, [(X, RX), (Y, RY), (RX, X), (RY, Y), (Z, Z), (RZ, RZ)]),
+        ];
+        for (scheme, routes) in cases {
+            for (from, to) in routes {
+                assert_eq!(scheme.route(from), to);
+            }
+        }
+    }
+
+    #[test]
+    fn stick_settings_only_go_in_the_stick_table() {
+        let error = load_error("sensitivity = 100.0", None);
+        assert!(error.contains("unknown field `sensitivity`"), "{error}");
+    }
+
+    #[test]
+    fn sender_thread_settings_are_checked() {
+        assert_eq!(
+            load_error("sender_thread = true\nsend_full = \"retry\"", None),
+            "Invalid config in test.toml: send_full can't be used with sender_thread, which only logs errors"
+        );
+        assert_eq!(
+            load_error("sender_thread_cpu = 1", None),
+            "Invalid config in test.toml: sender_thread_priority and sender_thread_cpu need sender_thread on"
+        );
+        assert_eq!(
+            load_error("sender_thread = true\nsender_thread_priority = 100", None),
+            "Invalid config in test.toml: sender_thread_priority must be between 1 and 99"
+        );
+        assert!(load(
+            "sender_thread = true\nsender_thread_priority = 10\nsender_thread_cpu = 0",
+            None
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn set_value_keeps_the_file_loading() {
+        let dir = std::env::temp_dir().join(format!("mouse-con-set-value-{}", std::process::id()));
+        let path = dir.join("config.toml");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let set = |text: &str, value: f64| {
+            std::fs::write(&path, text).unwrap();
+            let result = set_value(&path, Some("stick"), "sensitivity", value.into());
+            (result, std::fs::read_to_string(&path).unwrap())
+        };
+        let sensitivity = |text: &str| load(text, None).unwrap().stick.sensitivity;
+
+        // Edited in place, keeping the comments
+        let text =
+            "# mine\n[stick] # aiming\nsensitivity = 100.0\n\n[device]\nkind = \"xbox360\"\n";
+        let (result, saved) = set(text, 62.5);
+        result.unwrap();
+        assert_eq!(saved, text.replace("100.0", "62.5"));
+
+        // A multi-line array before the table doesn't get in the way
+        let text = "[compat]\ninverted_axes = [\n    \"RY\",\n]\n\n[stick]\nsensitivity = 100.0\n";
+        let (result, saved) = set(text, 62.5);
+        result.unwrap();
+        assert_eq!(saved, text.replace("100.0", "62.5"));
+
+        // The line editor can't handle these, so they're written out again
+        for text in [
+            "stick.sensitivity = 100.0\n",
+            "stick = { sensitivity = 100.0, deadzone = 2.0 }\n",
+            "[ stick ]\nsensitivity = 100.0\n",
+        ] {
+            let (result, saved) = set(text, 62.5);
+            result.unwrap();
+            assert_eq!(sensitivity(&saved), 62.5, "{saved}");
+        }
+        let (_, saved) = set("stick = { sensitivity = 100.0, deadzone = 2.0 }\n", 62.5);
+        assert_eq!(load(&saved, None).unwrap().stick.deadzone, 2.);
+
+        // Left alone when the new value doesn't load
+        let text = "[stick]\nsensitivity = 100.0\n";
+        let (result, saved) = set(text, -1.);
+        assert!(result.is_err());
+        assert_eq!(saved, text);
+
+        // A missing file gets just the new table
+        std::fs::remove_file(&path).unwrap();
+        set_value(&path, Some("stick"), "sensitivity", 80.0.into()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "[stick]\nsensitivity = 80.0\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,440 @@
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+use std::{
+    fs::File,
+    io::Write,
+    path::Path,
+    sync::mpsc,
+    thread::{self, JoinHandle},
+};
+
+use anyhow::Context;
+use nix::{
+    libc,
+    sched::{sched_setaffinity, CpuSet},
+    unistd::Pid,
+};
+use uinput::event::{
+    absolute::Position,
+    controller::{GamePad, JoyStick, TriggerHappy},
+    Absolute, Code, Controller, Kind,
+};
+
+use crate::{
+    config::{Compat, DeviceKind},
+    names, AXIS_MAX, AXIS_MIN,
+};
+
+const EV_SYN: u16 = 0;
+const SYN_REPORT: u16 = 0;
+
+pub const TRIGGER_MAX: i32 = 255;
+/// Stick axes the controller declares, ranging from `AXIS_MIN` to `AXIS_MAX`
+pub const STICK_AXES: [Position; 4] = [Position::X, Position::Y, Position::RX, Position::RY];
+/// Analog trigger axes the controller declares, ranging from 0 to `TRIGGER_MAX`
+pub const TRIGGER_AXES: [Position; 2] = [Position::Z, Position::RZ];
+
+/// How many buttons a generic gamepad can have, one for each joystick and extra button code
+pub const MAX_GENERIC_BUTTONS: u32 = 53;
+/// Axes a generic gamepad can have, it declares the first `axes` of these
+pub const GENERIC_AXES: [Position; 6] = [
+    Position::X,
+    Position::Y,
+    Position::RX,
+    Position::RY,
+    Position::Z,
+    Position::RZ,
+];
+
+/// Buttons a generic gamepad declares, in the order games number them
+pub fn generic_buttons() -> impl Iterator<Item = Controller> {
+    JoyStick::iter_variants()
+        .map(Controller::JoyStick)
+        .chain(TriggerHappy::iter_variants().map(Controller::TriggerHappy))
+}
+
+/// Which generic button a button is sent as. The named buttons are numbered in the order
+/// `--list-keys` prints them.
+fn generic_index(button: Controller) -> Option<usize> {
+    generic_buttons()
+        .position(|generic| generic == button)
+        .or_else(|| {
+            names::BUTTON_NAMES
+                .iter()
+                .position(|(_, named)| *named == button)
+        })
+}
+
+impl DeviceKind {
+    pub fn declares(&self, position: Position) -> bool {
+        match self {
+            Self::Xbox360 => STICK_AXES.contains(&position) || TRIGGER_AXES.contains(&position),
+            Self::Generic { axes, .. } => GENERIC_AXES[..*axes as usize].contains(&position),
+        }
+    }
+
+    pub fn has_button(&self, button: Controller) -> bool {
+        match self {
+            Self::Xbox360 => true,
+            Self::Generic { buttons, .. } => {
+                generic_index(button).is_some_and(|index| index < *buttons as usize)
+            }
+        }
+    }
+
+    /// The event that actually gets sent for `event`, or `None` if the device doesn't have it
+    pub fn output_event(&self, event: uinput::Event) -> Option<uinput::Event> {
+        match event {
+            uinput::Event::Controller(button) if matches!(self, Self::Generic { .. }) => {
+                let index = generic_index(button).filter(|_| self.has_button(button))?;
+                generic_buttons().nth(index).map(uinput::Event::Controller)
+            }
+            uinput::Event::Absolute(Absolute::Position(position)) if !self.declares(position) => {
+                None
+            }
+            event => Some(event),
+        }
+    }
+}
+
+impl Compat {
+    /// The value that actually gets sent for `event`
+    pub fn output_value(&self, event: uinput::Event, value: i32) -> i32 {
+        match event {
+            uinput::Event::Absolute(Absolute::Position(position))
+                if self.inverted_axes.iter().any(|axis| axis.0 == position) =>
+            {
+                // The range is one bigger on the positive side so flipping AXIS_MAX clamps
+                (-value).clamp(AXIS_MIN, AXIS_MAX)
+            }
+            _ => value,
+        }
+    }
+}
+
+pub fn is_trigger(position: Position) -> bool {
+    TRIGGER_AXES.contains(&position)
+}
+
+/// Where controller events get written to
+pub enum Device {
+    /// A virtual gamepad created through uinput
+    Uinput(uinput::Device),
+    /// An existing event device node, for devices made out of band
+    Node { file: File, analog_triggers: bool },
+    /// Prints events to stdout instead of sending them anywhere
+    Mock,
+    /// Hands events to a thread that writes them to another device, so the event loop never
+    /// waits on it
+    Threaded {
+        queue: Option<mpsc::Sender<Output>>,
+        thread: Option<JoinHandle<()>>,
+        analog_triggers: bool,
+    },
+    /// Keeps everything sent instead of writing it anywhere, for tests
+    #[cfg(test)]
+    Recording(Arc<Mutex<Recorded>>),
+}
+
+/// What a `Device::Recording` was sent
+#[cfg(test)]
+#[derive(Default)]
+pub struct Recorded {
+    pub outputs: Vec<Output>,
+    /// How many of the next events are turned away as if the buffer were full
+    pub full_for: usize,
+}
+
+/// What the sender thread of a `Device::Threaded` writes next
+pub enum Output {
+    Event(uinput::Event, i32),
+    Sync,
+}
+
+impl Device {
+    /// Creates a new virtual controller through uinput
+    pub fn create(kind: &DeviceKind) -> anyhow::Result<Self> {
+        let builder =
+            uinput::default().context("Did you forget to enable uinput kernel module?")?;
+        let mut builder = match kind {
+            DeviceKind::Xbox360 => builder
+                .name("Microsoft X-Box 360 pad")?
+                .vendor(0x045e)
+                .product(0x028e)
+                .vendor(0x110)
+                .event(Controller::All)?,
+            DeviceKind::Generic { buttons, .. } => {
+                let mut builder = builder.name("mouse-con generic gamepad")?;
+                for button in generic_buttons().take(*buttons as usize) {
+                    builder = builder.event(button)?;
+                }
+                builder
+            }
+        };
+
+        let axes = STICK_AXES.into_iter().chain(TRIGGER_AXES);
+        for position in axes.filter(|position| kind.declares(*position)) {
+            let (min, max) = if is_trigger(position) {
+                (0, TRIGGER_MAX)
+            } else {
+                (AXIS_MIN, AXIS_MAX)
+            };
+            builder = builder
+                .event(Absolute::Position(position))?
+                .min(min)
+                .max(max)
+                .flat(0)
+                .fuzz(0);
+        }
+
+        Ok(Self::Uinput(builder.create()?))
+    }
+
+    /// Opens an existing event device node (e.g. /dev/input/eventN) to write events into
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::options()
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open device {}", path.display()))?;
+        Ok(Self::Node {
+            file,
+            analog_triggers: node_has_analog_triggers(path),
+        })
+    }
+
+    /// Moves the device onto its own thread, which writes whatever gets sent in the order it
+    /// was sent. Errors can only be logged there, so they aren't returned anymore. The thread
+    /// can be given a real time `priority` and pinned to a `cpu`, which is checked before
+    /// returning.
+    pub fn threaded(mut self, priority: Option<i32>, cpu: Option<usize>) -> anyhow::Result<Self> {
+        let analog_triggers = self.has_analog_triggers();
+        let (queue, outputs) = mpsc::channel();
+        let (ready, scheduled) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("mouse-con sender".to_string())
+            .spawn(move || {
+                let result = schedule_thread(priority, cpu);
+                let failed = result.is_err();
+                let _ = ready.send(result);
+                if failed {
+                    return;
+                }
+
+                for output in outputs {
+                    let (result, what) = match output {
+                        Output::Event(event, value) => (self.send(event, value), "sending event"),
+                        Output::Sync => (self.synchronize(), "synchronizing event"),
+                    };
+                    if let Err(err) = result {
+                        eprintln!("Error while {what}: {err}");
+                    }
+                }
+            })
+            .context("Failed to start the sender thread")?;
+        scheduled
+            .recv()
+            .context("The sender thread stopped while starting")?
+            .context("Failed to set up the sender thread")?;
+
+        Ok(Self::Threaded {
+            queue: Some(queue),
+            thread: Some(thread),
+            analog_triggers,
+        })
+    }
+
+    /// Whether the analog triggers can be sent, for pressure bindings
+    pub fn has_analog_triggers(&self) -> bool {
+        match self {
+            Self::Uinput(_) | Self::Mock => true,
+            #[cfg(test)]
+            Self::Recording(_) => true,
+            Self::Node {
+                analog_triggers, ..
+            }
+            | Self::Threaded {
+                analog_triggers, ..
+            } => *analog_triggers,
+        }
+    }
+
+    fn enqueue(&self, output: Output) -> std::io::Result<()> {
+        let queue = match self {
+            Self::Threaded {
+                queue: Some(queue), ..
+            } => queue,
+            _ => unreachable!("only threaded devices have a queue"),
+        };
+        queue
+            .send(output)
+            .map_err(|_| std::io::Error::other("the sender thread stopped"))
+    }
+
+    pub fn send(&mut self, event: uinput::Event, value: i32) -> std::io::Result<()> {
+        match self {
+            Self::Uinput(device) => device.send(event, value).map_err(to_io_error),
+            Self::Node { file, .. } => {
+                write_input_event(file, event.kind() as u16, event.code() as u16, value)
+            }
+            Self::Mock => {
+                println!("{} {value}", names::event_name(event));
+                Ok(())
+            }
+            Self::Threaded { .. } => self.enqueue(Output::Event(event, value)),
+            #[cfg(test)]
+            Self::Recording(recorded) => {
+                let mut recorded = recorded.lock().unwrap();
+                if recorded.full_for > 0 {
+                    recorded.full_for -= 1;
+                    return Err(std::io::ErrorKind::WouldBlock.into());
+                }
+                recorded.outputs.push(Output::Event(event, value));
+                Ok(())
+            }
+        }
+    }
+
+    pub fn synchronize(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Uinput(device) => device.synchronize().map_err(to_io_error),
+            Self::Node { file, .. } => write_input_event(file, EV_SYN, SYN_REPORT, 0),
+            Self::Mock => {
+                println!("SYN");
+                Ok(())
+            }
+            Self::Threaded { .. } => self.enqueue(Output::Sync),
+            #[cfg(test)]
+            Self::Recording(recorded) => {
+                recorded.lock().unwrap().outputs.push(Output::Sync);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Gives the calling thread a real time `priority` and pins it to `cpu`, whichever are set
+fn schedule_thread(priority: Option<i32>, cpu: Option<usize>) -> anyhow::Result<()> {
+    if let Some(priority) = priority {
+        let param = libc::sched_param {
+            sched_priority: priority,
+        };
+        // Takes the calling thread with 0, this is only ever called from the thread itself
+        if unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) } != 0 {
+            return Err(std::io::Error::last_os_error()).with_context(|| {
+                format!("Failed to set SCHED_FIFO priority {priority} (this needs CAP_SYS_NICE or an rtprio limit)")
+            });
+        }
+    }
+
+    if let Some(cpu) = cpu {
+        let mut cpus = CpuSet::new();
+        cpus.set(cpu)
+            .map_err(anyhow::Error::from)
+            .and_then(|()| Ok(sched_setaffinity(Pid::from_raw(0), &cpus)?))
+            .with_context(|| format!("Failed to pin to CPU {cpu}"))?;
+    }
+
+    Ok(())
+}
+
+impl Drop for Device {
+    /// Lets the sender thread write out everything still queued before the device goes away
+    fn drop(&mut self) {
+        if let Self::Threaded { queue, thread, .. } = self {
+            drop(queue.take());
+            if let Some(thread) = thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+/// The analog trigger axis that goes with a trigger button
+pub fn trigger_axis(button: Controller) -> Option<Position> {
+    match button {
+        Controller::GamePad(GamePad::TL2) => Some(Position::Z),
+        Controller::GamePad(GamePad::TR2) => Some(Position::RZ),
+        _ => None,
+    }
+}
+
+/// Checks the absolute axis bitmap sysfs has for an event node, assuming no triggers if it
+/// can't be read
+fn node_has_analog_triggers(path: &Path) -> bool {
+    const ABS_Z: u32 = 0x02;
+    const ABS_RZ: u32 = 0x05;
+
+    let abs_bits = || -> Option<u64> {
+        let name = path.canonicalize().ok()?.file_name()?.to_owned();
+        let capabilities = Path::new("/sys/class/input")
+            .join(name)
+            .join("device/capabilities/abs");
+        // The bitmap is written as hex words, most significant first
+        let text = std::fs::read_to_string(capabilities).ok()?;
+        u64::from_str_radix(text.split_whitespace().last()?, 16).ok()
+    };
+    abs_bits().is_some_and(|bits| bits & (1 << ABS_Z) != 0 && bits & (1 << ABS_RZ) != 0)
+}
+
+/// Writes a raw `struct input_event`, the kernel fills in the timestamp
+fn write_input_event(file: &mut File, kind: u16, code: u16, value: i32) -> std::io::Result<()> {
+    const TIMEVAL_SIZE: usize = 2 * std::mem::size_of::<std::ffi::c_long>();
+
+    let mut event = [0; TIMEVAL_SIZE + 8];
+    event[TIMEVAL_SIZE..][..2].copy_from_slice(&kind.to_ne_bytes());
+    event[TIMEVAL_SIZE + 2..][..2].copy_from_slice(&code.to_ne_bytes());
+    event[TIMEVAL_SIZE + 4..].copy_from_slice(&value.to_ne_bytes());
+    file.write_all(&event)
+}
+
+fn to_io_error(err: uinput::Error) -> std::io::Error {
+    match err {
+        uinput::Error::Nix(nix::Error::Sys(errno)) => {
+            std::io::Error::from_raw_os_error(errno as i32)
+        }
+        err => std::io::Error::other(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threaded_writes_everything_in_order_before_dropping() {
+        let recorded = Arc::<Mutex<Recorded>>::default();
+        let mut device = Device::Recording(recorded.clone())
+            .threaded(None, None)
+            .unwrap();
+        let position = |value| (Position::RX.into(), value);
+        let press = |value| (Controller::GamePad(GamePad::A).into(), value);
+        let burst: Vec<(uinput::Event, i32)> = (0..1000)
+            .map(|i| {
+                if i % 10 == 0 {
+                    press(i / 10 % 2)
+                } else {
+                    position(i)
+                }
+            })
+            .collect();
+        for (event, value) in &burst {
+            device.send(*event, *value).unwrap();
+        }
+        device.synchronize().unwrap();
+        drop(device);
+
+        let mut expected: Vec<String> = burst
+            .iter()
+            .map(|(event, value)| format!("{} {value}", names::event_name(*event)))
+            .collect();
+        expected.push("SYN".to_string());
+        let written: Vec<String> = std::mem::take(&mut recorded.lock().unwrap().outputs)
+            .into_iter()
+            .map(|output| match output {
+                Output::Event(event, value) => format!("{} {value}", names::event_name(event)),
+                Output::Sync => "SYN".to_string(),
+            })
+            .collect();
+        assert_eq!(written, expected);
+    }
+}
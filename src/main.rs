@@ -1,20 +1,52 @@
-use std::{collections::HashMap, process::Command, time::Duration};
+mod calibrate;
+mod cli;
+mod config;
+mod device;
+mod names;
+mod script;
+mod timing;
 
-use anyhow::Context;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    process::{Command, ExitCode},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use cli::Args;
+use config::{
+    Binding, CameraLockMotion, CompositeOrder, Config, Direction, LayerMode, MovementMagnitude,
+    PauseRelease, Rounding, SendFullStrategy, StickSettings, UnmappedKeys,
+};
+use device::Device;
+use names::{Button, Key};
+use timing::Timing;
 use uinput::event::{
     absolute::Position,
     controller::{DPad, GamePad},
     Absolute, Controller,
 };
 use winit::application::ApplicationHandler;
-use winit::event::WindowEvent;
+use winit::event::{MouseScrollDelta, WindowEvent};
 use winit::{
     event_loop::{ActiveEventLoop, ControlFlow, EventLoop},
     keyboard::PhysicalKey,
 };
 use winit::{keyboard::KeyCode, window::WindowId};
 
-const MOUSE_SENSITIVITY: f64 = 250.;
+const AXIS_MIN: i32 = -127;
+const AXIS_MAX: i32 = 128;
+const SEND_RETRIES: u32 = 3;
+const SEND_RETRY_BACKOFF: Duration = Duration::from_micros(500);
+const PENDING_FLUSH_DELAY: Duration = Duration::from_millis(1);
+const WHEEL_DECAY_TICK: Duration = Duration::from_millis(10);
+const SPEED_DECAY_TICK: Duration = Duration::from_millis(10);
+const RAMP_TICK: Duration = Duration::from_millis(5);
+const SMOOTHING_TICK: Duration = Duration::from_millis(5);
+const MAX_DEADZONE: f64 = 64.;
+const VERTICAL_SCALE_RANGE: std::ops::RangeInclusive<f64> = 0.1..=5.;
+const XBANISH_MAX_BACKOFF: Duration = Duration::from_secs(60);
 
 fn key_to_controller_event(key: KeyCode) -> Option<Controller> {
     Some(match key {
@@ -49,214 +81,2789 @@ fn mouse_button_to_controller_event(button: u32) -> Option<Controller> {
     })
 }
 
-fn key_to_position(key: KeyCode) -> Option<(uinput::event::absolute::Position, i32)> {
+fn key_to_position(
+    key: KeyCode,
+    magnitude: &MovementMagnitude,
+) -> Option<(uinput::event::absolute::Position, i32)> {
     Some(match key {
-        KeyCode::KeyW => (Position::Y, -127),
-        KeyCode::KeyA => (Position::X, -127),
-        KeyCode::KeyS => (Position::Y, 128),
-        KeyCode::KeyD => (Position::X, 128),
+        KeyCode::KeyW => (Position::Y, -magnitude.forward),
+        KeyCode::KeyA => (Position::X, -magnitude.left),
+        KeyCode::KeyS => (Position::Y, magnitude.backward),
+        KeyCode::KeyD => (Position::X, magnitude.right),
         _ => return None,
     })
 }
 
 struct AppState {
-    device: uinput::Device,
+    config: Rc<Config>,
+    /// The profiles the profile cycle key switches between, and which one is in use
+    profile_cycle: Vec<(String, Rc<Config>)>,
+    profile_index: Option<usize>,
+    // When the profile cycle key last switched profiles, for debouncing it
+    profile_switched_at: Option<Instant>,
+    /// The config file snapshots are saved to
+    snapshot_path: Option<PathBuf>,
+    device: Device,
     xbanish_proc: Option<std::process::Child>,
-    position_held_map: HashMap<Position, Vec<i32>>,
+    xbanish_check_at: Option<Instant>,
+    /// Restarts since xbanish was last seen running, which backs off the next check
+    xbanish_restarts: u32,
+    // Whether input is being sent, toggled together with hiding the cursor
+    active: bool,
+    // Nothing is captured so there's no cursor to hide
+    headless: bool,
+    // Whether pressure bindings can pull the analog triggers, otherwise they press the buttons
+    analog_triggers: bool,
+    // Values pushed onto each axis by the keys currently held
+    position_held_map: HashMap<Position, Vec<(KeyCode, i32)>>,
+    // Last value sent for each axis so it can be re-sent by the neutral hold
+    axis_values: HashMap<Position, i32>,
+    recenter_at: Option<Instant>,
+    neutral_hold_at: Option<Instant>,
+    // Where the movement ramp is heading and where it currently is for each left stick axis
+    movement_target: HashMap<Position, i32>,
+    movement_current: HashMap<Position, f64>,
+    ramp_at: Option<Instant>,
+    // When the left stick goes back to center after the last movement key was released
+    movement_stop_at: Option<Instant>,
+    held_buttons: HashSet<uinput::Event>,
+    held_keys: HashSet<KeyCode>,
+    // Keys with a toggle binding that are currently latched on
+    toggled_keys: HashSet<KeyCode>,
+    /// Which button of their `cycle` each cycling binding sends next
+    cycle_positions: HashMap<KeyCode, usize>,
+    turbos: HashMap<KeyCode, Turbo>,
+    layer_active: bool,
+    // Latest mouse motion, zero once the mouse stops
+    mouse_delta: (f64, f64),
+    /// What the mouse on its own last sent to the axes it drives, before the look keys
+    mouse_stick: (f64, f64),
+    /// Look keys held down, in the order they were pressed
+    look_keys_held: Vec<KeyCode>,
+    /// Keys without a mapping held down, for the `unmapped_keys` button
+    unmapped_held: HashSet<KeyCode>,
+    // Whether each direction bind is armed (holding its button)
+    direction_binds_active: Vec<bool>,
+    watchdog_at: Option<Instant>,
+    // Nothing is sent until this passes, so a game started meanwhile sees a clean first state
+    startup_at: Option<Instant>,
+    // Right stick value frozen while the camera lock key is held
+    camera_lock: Option<(i32, i32)>,
+    // Mouse motion added up while the camera lock is held, when accumulating it
+    camera_lock_motion: (f64, f64),
+    // Right stick deadzone, adjustable while running
+    deadzone: f64,
+    // Whether the deadzone off key is held
+    deadzone_off: bool,
+    // Vertical to horizontal mouse scale, adjustable while running
+    vertical_scale: f64,
+    // Whether the sensitivity toggle is on the alternate sensitivity
+    alternate_sensitivity: bool,
+    // Whether the `button_mode` button is held, switching to its stick settings
+    button_mode_held: bool,
+    // Set on unpausing so the next mouse motion is taken as a baseline, for `unpause_baseline`
+    unpaused: bool,
+    // When the mouse axes were last recentered by a timer or reset, for `recenter_echo_ms`
+    recentered_at: Option<Instant>,
+    // Whether the last mouse motion sent a value small enough to be held instead of recentered
+    holding_small: bool,
+    // Smoothing filter state for the right stick
+    smoothed: (f64, f64),
+    // Left stick offset from scrolling the mouse wheel
+    wheel_offset: f64,
+    wheel_decay_at: Option<Instant>,
+    // How far the speed trigger is pulled, from 0 to 1
+    speed_level: f64,
+    speed_decay_at: Option<Instant>,
+    // When to flush the mouse axes with centered reports, once they've been centered a while
+    drift_flush_at: Option<Instant>,
+    no_sync: bool,
+    batching: bool,
+    batch_needs_sync: bool,
+    timing: Option<Timing>,
+    // Events that couldn't be written because the device buffer was full, in the order they were
+    // sent. Axes only keep their latest value, buttons keep every press and release.
+    pending_events: VecDeque<(uinput::Event, i32)>,
+    flush_pending_at: Option<Instant>,
+    // When each button was last pressed, and releases held back until it's been down for
+    // `min_press_ms`
+    pressed_at: HashMap<uinput::Event, Instant>,
+    pending_releases: HashMap<uinput::Event, Instant>,
+    send_stats: SendStats,
+}
+
+/// A turbo or repeat binding that's engaged, repeatedly pressing and releasing its buttons
+struct Turbo {
+    /// With how far to pull each one if it's a trigger
+    buttons: Vec<(Button, Option<f64>)>,
+    half_period: Duration,
+    down: bool,
+    next_at: Instant,
+}
+
+#[derive(Default)]
+struct SendStats {
+    dropped: u64,
+    coalesced: u64,
 }
 
 impl AppState {
-    fn new() -> anyhow::Result<Self> {
-        let mut builder = uinput::default()
-            .context("Did you forget to enable uinput kernel module?")?
-            .name("Microsoft X-Box 360 pad")?
-            .vendor(0x045e)
-            .product(0x028e)
-            .vendor(0x110)
-            .event(Controller::All)?;
-
-        fn add_pos_event(
-            device_builder: uinput::device::Builder,
-            event: impl Into<uinput::Event>,
-        ) -> anyhow::Result<uinput::device::Builder> {
-            Ok(device_builder
-                .event(event)?
-                .min(-127)
-                .max(128)
-                .flat(0)
-                .fuzz(0))
-        }
-
-        builder = add_pos_event(builder, Absolute::Position(Position::X))?;
-        builder = add_pos_event(builder, Absolute::Position(Position::Y))?;
-        builder = add_pos_event(builder, Absolute::Position(Position::RX))?;
-        builder = add_pos_event(builder, Absolute::Position(Position::RY))?;
+    fn new(config: Config, args: &Args) -> anyhow::Result<Self> {
+        let device = match &args.device_path {
+            _ if args.mock_device => Device::Mock,
+            Some(path) => Device::open(path)?,
+            None => Device::create(&config.device)?,
+        };
+        let device = if config.sender_thread {
+            device.threaded(config.sender_thread_priority, config.sender_thread_cpu)?
+        } else {
+            device
+        };
+
+        let analog_triggers = device.has_analog_triggers()
+            && config.device.declares(Position::Z)
+            && config.device.declares(Position::RZ);
+        let layer_bindings = config
+            .layer
+            .iter()
+            .flat_map(|layer| layer.bindings.values());
+        let has_pressure = config
+            .bindings
+            .values()
+            .chain(layer_bindings)
+            .any(|binding| binding.pressure.is_some() || binding.triggers.is_some());
+        if has_pressure && !analog_triggers {
+            eprintln!("Warning: the device has no analog triggers, pressure bindings press TL2/TR2 fully instead");
+        }
 
+        let profile_cycle: Vec<_> = config
+            .load_profile_cycle(&args.config_paths)?
+            .into_iter()
+            .map(|(name, config)| (name, Rc::new(config)))
+            .collect();
+        let profile_index = args
+            .profile
+            .as_ref()
+            .and_then(|profile| profile_cycle.iter().position(|(name, _)| name == profile));
+
+        let direction_binds_active = vec![false; config.direction_binds.len()];
+        let neutral_hold_at = config
+            .neutral_hold_period()
+            .map(|period| Instant::now() + period);
         Ok(Self {
-            device: builder.create()?,
+            deadzone: config.stick.deadzone,
+            deadzone_off: false,
+            vertical_scale: config.stick.vertical_scale,
+            alternate_sensitivity: false,
+            button_mode_held: false,
+            unpaused: false,
+            config: Rc::new(config),
+            profile_cycle,
+            profile_index,
+            profile_switched_at: None,
+            snapshot_path: config::save_path(&args.config_paths),
+            device,
             xbanish_proc: None,
+            xbanish_check_at: None,
+            xbanish_restarts: 0,
+            active: false,
+            headless: args.script.is_some(),
+            analog_triggers,
             position_held_map: HashMap::default(),
+            axis_values: HashMap::default(),
+            recenter_at: None,
+            neutral_hold_at,
+            movement_target: HashMap::default(),
+            movement_current: HashMap::default(),
+            ramp_at: None,
+            movement_stop_at: None,
+            held_buttons: HashSet::default(),
+            held_keys: HashSet::default(),
+            toggled_keys: HashSet::default(),
+            cycle_positions: HashMap::default(),
+            turbos: HashMap::default(),
+            layer_active: false,
+            mouse_delta: (0., 0.),
+            mouse_stick: (0., 0.),
+            look_keys_held: Vec::new(),
+            unmapped_held: HashSet::new(),
+            direction_binds_active,
+            watchdog_at: None,
+            startup_at: None,
+            camera_lock: None,
+            camera_lock_motion: (0., 0.),
+            recentered_at: None,
+            holding_small: false,
+            smoothed: (0., 0.),
+            wheel_offset: 0.,
+            wheel_decay_at: None,
+            speed_level: 0.,
+            speed_decay_at: None,
+            drift_flush_at: None,
+            no_sync: args.no_sync,
+            batching: false,
+            batch_needs_sync: false,
+            timing: args.timing.then(Timing::default),
+            pending_events: VecDeque::new(),
+            flush_pending_at: None,
+            pressed_at: HashMap::default(),
+            pending_releases: HashMap::default(),
+            send_stats: SendStats::default(),
         })
     }
 
     fn send(&mut self, event: impl Into<uinput::Event>, value: i32) {
-        if !self.mouse_hiding() {
+        if !self.active || self.starting() {
             return;
         }
 
         let event = event.into();
-        // eprintln!("Sending: {:?}, {value}", event);
-        if let Err(err) = self.device.send(event, value) {
-            eprintln!("Error while sending event: {err}");
+        if let Some(min_press) = self.config.min_press() {
+            if !matches!(event, uinput::Event::Absolute(_)) {
+                if value != 0 {
+                    // Let go of a release that's still waiting so the new press shows up as one
+                    if self.pending_releases.remove(&event).is_some() {
+                        self.send_event(event, 0);
+                    }
+                    self.pressed_at.insert(event, Instant::now());
+                } else if let Some(release_at) = self
+                    .pressed_at
+                    .remove(&event)
+                    .map(|at| at + min_press)
+                    .filter(|at| *at > Instant::now())
+                {
+                    self.pending_releases.insert(event, release_at);
+                    return;
+                }
+            }
+        }
+
+        self.send_event(event, value);
+    }
+
+    fn send_event(&mut self, event: uinput::Event, value: i32) {
+        match event {
+            uinput::Event::Absolute(Absolute::Position(position)) => {
+                self.axis_values.insert(position, value);
+            }
+            _ if value != 0 => {
+                self.held_buttons.insert(event);
+            }
+            _ => {
+                self.held_buttons.remove(&event);
+            }
+        }
+
+        // Anything held back earlier has to go out first to keep the order, and if it still can't
+        // this waits behind it
+        self.flush_pending_events();
+        if self.pending_events.is_empty() {
+            // eprintln!("Sending: {:?}, {value}", event);
+            self.write_event(event, value);
+        } else {
+            self.hold_back_event(event, value);
+        }
+        self.end_report();
+    }
+
+    /// Synchronizes after an event, or once at the end when inside `batch`
+    fn end_report(&mut self) {
+        if self.batching {
+            self.batch_needs_sync = true;
+        } else {
+            self.synchronize();
+        }
+    }
+
+    /// Sends every event from `f` under a single synchronize so the game sees them together
+    fn batch(&mut self, f: impl FnOnce(&mut Self)) {
+        let was_batching = std::mem::replace(&mut self.batching, true);
+        f(self);
+        self.batching = was_batching;
+        if !was_batching && std::mem::take(&mut self.batch_needs_sync) {
+            self.synchronize();
         }
+    }
+
+    fn write_event(&mut self, event: uinput::Event, value: i32) {
+        match self.try_write_event(event, value) {
+            Ok(()) => (),
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => match self.config.send_full
+            {
+                SendFullStrategy::Coalesce => self.hold_back_event(event, value),
+                SendFullStrategy::Log | SendFullStrategy::Retry => {
+                    self.send_stats.dropped += 1;
+                    eprintln!("Error while sending event: {err}");
+                }
+            },
+            Err(err) => eprintln!("Error while sending event: {err}"),
+        }
+    }
+
+    fn try_write_event(&mut self, event: uinput::Event, value: i32) -> std::io::Result<()> {
+        let output = match self.config.device.output_event(event) {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+        let output_value = self.config.compat.output_value(event, value);
+        self.retry_if_full(|device| device.send(output, output_value))
+    }
 
-        if let Err(err) = self.device.synchronize() {
+    /// Queues an event to be written once the device has room. An axis waiting with nothing but
+    /// other axes behind it just takes the new value, anything else goes at the back.
+    fn hold_back_event(&mut self, event: uinput::Event, value: i32) {
+        self.flush_pending_at
+            .get_or_insert_with(|| Instant::now() + PENDING_FLUSH_DELAY);
+        if let uinput::Event::Absolute(_) = event {
+            let waiting = self
+                .pending_events
+                .iter_mut()
+                .rev()
+                .take_while(|(pending, _)| matches!(pending, uinput::Event::Absolute(_)))
+                .find(|(pending, _)| *pending == event);
+            if let Some((_, pending_value)) = waiting {
+                *pending_value = value;
+                self.send_stats.coalesced += 1;
+                return;
+            }
+        }
+        self.pending_events.push_back((event, value));
+    }
+
+    fn synchronize(&mut self) {
+        if self.no_sync {
+            return;
+        }
+
+        if let Err(err) = self.retry_if_full(|device| device.synchronize()) {
             eprintln!("Error while synchronizing event: {err}");
         }
     }
 
+    fn retry_if_full(
+        &mut self,
+        mut write: impl FnMut(&mut Device) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        let mut result = write(&mut self.device);
+        if self.config.send_full == SendFullStrategy::Retry {
+            for _ in 0..SEND_RETRIES {
+                if !result
+                    .as_ref()
+                    .is_err_and(|err| err.kind() == std::io::ErrorKind::WouldBlock)
+                {
+                    break;
+                }
+
+                std::thread::sleep(SEND_RETRY_BACKOFF);
+                result = write(&mut self.device);
+            }
+        }
+
+        result
+    }
+
+    /// Writes the held back events in order, stopping at the first one there's still no room
+    /// for so nothing overtakes it
+    fn flush_pending_events(&mut self) {
+        if self.pending_events.is_empty() {
+            return;
+        }
+
+        self.flush_pending_at = None;
+        let mut written = false;
+        while let Some((event, value)) = self.pending_events.pop_front() {
+            match self.try_write_event(event, value) {
+                Ok(()) => written = true,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                    self.pending_events.push_front((event, value));
+                    self.flush_pending_at = Some(Instant::now() + PENDING_FLUSH_DELAY);
+                    break;
+                }
+                Err(err) => eprintln!("Error while sending event: {err}"),
+            }
+        }
+        if written {
+            self.end_report();
+        }
+    }
+
+    /// Tracks which keys are held, returning false for key presses that are just autorepeat
+    fn key_changed(&mut self, key: KeyCode, pressed: bool) -> bool {
+        if pressed {
+            self.held_keys.insert(key)
+        } else {
+            self.held_keys.remove(&key)
+        }
+    }
+
     fn do_key(&mut self, key: winit::keyboard::KeyCode, pressed: bool) {
-        if let Some((position, value)) = key_to_position(key) {
-            let position_helds = self.position_held_map.entry(position).or_default();
+        if self
+            .config
+            .direction_binds
+            .iter()
+            .any(|bind| bind.modifier.is_some_and(|modifier| modifier.0 == key))
+        {
+            self.update_direction_binds(self.mouse_delta);
+        }
+
+        if is_hotkey(self.config.profile_cycle_key, key) {
             if pressed {
-                position_helds.push(value);
-            } else {
-                while let Some(i) = position_helds.iter().position(|v| *v == value) {
-                    position_helds.remove(i);
+                self.cycle_profile();
+            }
+            return;
+        }
+
+        if is_hotkey(self.config.snapshot_key, key) {
+            if pressed {
+                self.save_snapshot();
+            }
+            return;
+        }
+
+        if is_hotkey(self.config.reset_key, key) {
+            if pressed {
+                eprintln!("Resetting all inputs");
+                self.reset_all();
+            }
+            return;
+        }
+
+        if is_hotkey(self.config.camera_lock_key, key) {
+            self.set_camera_lock(pressed);
+            return;
+        }
+
+        if let Some(layer) = &self.config.layer {
+            if layer.key.0 == key {
+                let active = match layer.mode {
+                    LayerMode::Hold => pressed,
+                    LayerMode::Toggle if pressed => !self.layer_active,
+                    LayerMode::Toggle => self.layer_active,
+                };
+                let button = layer.button;
+                // The layer switches before the button goes down and after it comes up, so the
+                // button never overlaps with keys the switch releases
+                if pressed {
+                    self.set_layer_active(active);
+                }
+                if let Some(button) = button {
+                    self.send(button.0, if pressed { 1 } else { 0 });
                 }
+                if !pressed {
+                    self.set_layer_active(active);
+                }
+                return;
+            }
+        }
+
+        if is_hotkey(self.config.deadzone_up_key, key) {
+            if pressed {
+                self.adjust_deadzone(self.config.deadzone_step);
+            }
+            return;
+        }
+
+        if is_hotkey(self.config.deadzone_off_key, key) {
+            self.deadzone_off = pressed;
+            return;
+        }
+
+        if is_hotkey(self.config.deadzone_down_key, key) {
+            if pressed {
+                self.adjust_deadzone(-self.config.deadzone_step);
+            }
+            return;
+        }
+
+        let toggle_key = self
+            .config
+            .sensitivity_toggle
+            .as_ref()
+            .map(|toggle| toggle.key);
+        if is_hotkey(toggle_key, key) {
+            if pressed {
+                self.alternate_sensitivity = !self.alternate_sensitivity;
+                eprintln!("Sensitivity: {}", self.sensitivity());
+            }
+            return;
+        }
+
+        if is_hotkey(self.config.vertical_scale_up_key, key) {
+            if pressed {
+                self.adjust_vertical_scale(self.config.vertical_scale_step);
+            }
+            return;
+        }
+
+        if is_hotkey(self.config.vertical_scale_down_key, key) {
+            if pressed {
+                self.adjust_vertical_scale(-self.config.vertical_scale_step);
             }
+            return;
+        }
+
+        if self.config.look_keys.keys.contains_key(&Key(key)) {
+            self.hold_look_key(key, pressed);
+            return;
+        }
 
-            let sum = position_helds.iter().sum();
-            self.send(Absolute::Position(position), sum);
+        if let Some(binding) = self.binding_for(key) {
+            if !binding.toggle {
+                self.send_binding(key, pressed);
+            } else if pressed {
+                // Toggle bindings latch on one press and let go on the next
+                let engaged = !self.toggled_keys.remove(&key);
+                if engaged {
+                    self.toggled_keys.insert(key);
+                }
+                self.send_binding(key, engaged);
+            }
+        } else if let Some((position, value)) =
+            key_to_position(key, &self.config.movement_magnitude)
+        {
+            self.hold_axis(key, position, value, pressed);
         } else if let Some(uinput_event) = key_to_controller_event(key) {
             self.send(uinput_event, if pressed { 1 } else { 0 });
+        } else {
+            self.do_unmapped_key(key, pressed);
         }
     }
 
-    fn do_mouse_button(&mut self, button: u32, pressed: bool) {
-        if let Some(uinput_event) = mouse_button_to_controller_event(button) {
-            self.send(uinput_event, if pressed { 1 } else { 0 });
+    fn do_unmapped_key(&mut self, key: KeyCode, pressed: bool) {
+        match self.config.unmapped_keys {
+            UnmappedKeys::Ignore => (),
+            UnmappedKeys::Log => {
+                if pressed {
+                    eprintln!("Unmapped key: {}", names::key_name(key));
+                }
+            }
+            UnmappedKeys::Button { button } => {
+                // Held until the last of them is released
+                let was_held = !self.unmapped_held.is_empty();
+                if pressed {
+                    self.unmapped_held.insert(key);
+                } else {
+                    self.unmapped_held.remove(&key);
+                }
+                let held = !self.unmapped_held.is_empty();
+                if held != was_held {
+                    self.send(button.0, if held { 1 } else { 0 });
+                }
+            }
         }
     }
 
-    fn do_mouse_move(&mut self, delta: (f64, f64)) {
-        let range = 10. / MOUSE_SENSITIVITY;
-        let mut stick_x = map_range(delta.0, -range, range, -127., 128.);
-        let mut stick_y = map_range(delta.1, -range, range, -127., 128.) * 1.5;
+    /// Pushes or lets go of everything a key's binding sends. Turbo and repeat bindings keep
+    /// pulsing their buttons for as long as they're engaged.
+    fn send_binding(&mut self, key: KeyCode, engaged: bool) {
+        let binding = match self.binding_for(key) {
+            Some(binding) => binding.clone(),
+            None => return,
+        };
+        let pulse = binding.pulse().filter(|_| engaged);
+        let buttons: Vec<_> = if binding.cycle.is_empty() {
+            binding.all_buttons().collect()
+        } else {
+            let index = self.cycle_positions.get(&key).copied().unwrap_or(0);
+            if !engaged {
+                // Let go of the current button, the next press sends the one after it
+                self.cycle_positions
+                    .insert(key, binding.next_in_cycle(index));
+            }
+            binding.cycle.get(index).copied().into_iter().collect()
+        };
+        let buttons_first = self.config.composite_order == CompositeOrder::ButtonsFirst;
+        self.batch(|state| {
+            let send_axis = |state: &mut Self| {
+                if let Some(axis) = binding.axis {
+                    state.hold_axis(key, axis.0, binding.value, engaged);
+                }
+            };
+            let send_button = |state: &mut Self| {
+                for button in &buttons {
+                    state.press_button(*button, binding.pressure_for(*button), engaged);
+                }
+            };
 
-        stick_x = stick_x.signum() * stick_x.abs().sqrt();
-        stick_y = stick_y.signum() * stick_y.abs().sqrt();
+            if buttons_first {
+                send_button(state);
+                send_axis(state);
+            } else {
+                send_axis(state);
+                send_button(state);
+            }
+        });
 
-        // Send right analog stick input through uinput
-        self.send(Absolute::Position(Position::RX), stick_x as i32);
-        self.send(Absolute::Position(Position::RY), stick_y as i32);
+        match pulse {
+            Some((first_release, half_period)) if !buttons.is_empty() => {
+                let turbo = Turbo {
+                    buttons: buttons
+                        .iter()
+                        .map(|button| (*button, binding.pressure_for(*button)))
+                        .collect(),
+                    half_period,
+                    down: true,
+                    next_at: Instant::now() + first_release,
+                };
+                self.turbos.insert(key, turbo);
+            }
+            _ => {
+                self.turbos.remove(&key);
+            }
+        }
     }
 
-    fn do_recenter(&mut self, pos1: Position, pos2: Position) {
-        self.send(Absolute::Position(pos1), 0);
-        self.send(Absolute::Position(pos2), 0);
+    /// Flips the buttons of a turbo binding between pressed and released
+    fn step_turbo(&mut self, key: KeyCode, now: Instant) {
+        let (buttons, down) = match self.turbos.get_mut(&key) {
+            Some(turbo) => {
+                turbo.down = !turbo.down;
+                turbo.next_at = now + turbo.half_period;
+                (turbo.buttons.clone(), turbo.down)
+            }
+            None => return,
+        };
+        self.batch(|state| {
+            for (button, pressure) in buttons {
+                state.press_button(button, pressure, down);
+            }
+        });
     }
 
-    fn hide_mouse(&mut self, hide: bool) {
-        if hide {
-            self.xbanish_proc = Command::new("xbanish")
-                .args(["-a", "-i", "mod4", "-m", "se"])
-                .spawn()
-                .inspect_err(|err| eprintln!("Failed to run xbanish: {err}"))
-                .ok();
-        } else if let Some(mut process) = self.xbanish_proc.take() {
-            process.kill().unwrap();
-            process.wait().unwrap();
+    /// Presses or releases a bound button. With a pressure, trigger buttons pull their analog
+    /// trigger that far instead when the device has them.
+    fn press_button(&mut self, button: Button, pressure: Option<f64>, down: bool) {
+        let trigger = device::trigger_axis(button.0).filter(|_| self.analog_triggers);
+        match (trigger, pressure) {
+            (Some(axis), Some(pressure)) => {
+                let value = if down {
+                    (pressure * device::TRIGGER_MAX as f64).round() as i32
+                } else {
+                    0
+                };
+                self.send(Absolute::Position(axis), value);
+            }
+            _ => self.send(button.0, if down { 1 } else { 0 }),
         }
     }
 
-    fn mouse_hiding(&self) -> bool {
-        self.xbanish_proc.is_some()
+    fn binding_for(&self, key: KeyCode) -> Option<&Binding> {
+        let layer_binding = self
+            .config
+            .layer
+            .as_ref()
+            .filter(|_| self.layer_active)
+            .and_then(|layer| layer.bindings.get(&Key(key)));
+        layer_binding.or_else(|| self.config.bindings.get(&Key(key)))
     }
-}
 
-fn map_range(x: f64, in_min: f64, in_max: f64, out_min: f64, out_max: f64) -> f64 {
-    (x - in_min) * (out_max - out_min) / (in_max - in_min) + out_min
-}
+    fn set_layer_active(&mut self, active: bool) {
+        if active == self.layer_active {
+            return;
+        }
 
-#[derive(Default)]
-struct App {
-    state: Option<AppState>,
-}
+        // Release held and toggled keys the layer rebinds so nothing stays stuck down from the old
+        // layer. Held ones are forgotten so letting go of them doesn't release the new binding,
+        // which was never pressed.
+        let layer = match &self.config.layer {
+            Some(layer) => layer,
+            None => return,
+        };
+        let rebound = |keys: &HashSet<KeyCode>| -> Vec<KeyCode> {
+            keys.iter()
+                .copied()
+                .filter(|key| layer.bindings.contains_key(&Key(*key)))
+                .collect()
+        };
+        let (toggled_keys, held_keys) = (rebound(&self.toggled_keys), rebound(&self.held_keys));
+        for key in toggled_keys {
+            self.toggled_keys.remove(&key);
+            self.send_binding(key, false);
+        }
+        for key in held_keys {
+            self.held_keys.remove(&key);
+            self.do_key(key, false);
+        }
 
-impl ApplicationHandler for App {
-    fn resumed(&mut self, _: &ActiveEventLoop) {
-        let mut state = AppState::new().expect("Failed to create data");
-        // Center joystick
-        state.do_recenter(Position::X, Position::Y);
-        state.do_recenter(Position::RX, Position::RY);
-        state.hide_mouse(true);
-        self.state = Some(state);
+        self.layer_active = active;
     }
 
-    fn window_event(&mut self, _: &ActiveEventLoop, _: WindowId, _: WindowEvent) {}
+    fn hold_axis(&mut self, key: KeyCode, position: Position, value: i32, pressed: bool) {
+        let position_helds = self.position_held_map.entry(position).or_default();
+        position_helds.retain(|(held_key, _)| *held_key != key);
+        if pressed {
+            position_helds.push((key, value));
+        }
 
-    fn new_events(&mut self, event_loop: &ActiveEventLoop, cause: winit::event::StartCause) {
-        let state = match self.state.as_mut() {
-            Some(state) => state,
-            None => return,
-        };
+        let released_all = self.position_held_map.values().all(Vec::is_empty);
+        if self.config.instant_stop && released_all && !pressed {
+            self.stop_movement();
+            return;
+        }
+
+        if let Some(grace) = self
+            .config
+            .movement_release_grace()
+            .filter(|_| released_all && !pressed)
+        {
+            self.movement_stop_at = Some(Instant::now() + grace);
+            return;
+        }
 
-        if matches!(cause, winit::event::StartCause::ResumeTimeReached { .. }) {
-            state.do_recenter(Position::RX, Position::RY);
-            event_loop.set_control_flow(ControlFlow::Wait);
+        if self.movement_stop_at.take().is_some() {
+            // Pressed again during the grace period, so the other axis still has its old value
+            self.send_movement_axis(Position::X);
+            self.send_movement_axis(Position::Y);
+        } else {
+            self.send_movement_axis(position);
         }
     }
 
-    fn device_event(
-        &mut self,
-        event_loop: &ActiveEventLoop,
-        _: winit::event::DeviceId,
-        event: winit::event::DeviceEvent,
-    ) {
-        let state = match self.state.as_mut() {
-            Some(state) => state,
-            None => return,
-        };
+    /// Centers the left stick now, cutting short any release grace or ramp that's going on
+    fn stop_movement(&mut self) {
+        self.movement_stop_at = None;
+        self.ramp_at = None;
+        self.movement_target.clear();
+        self.movement_current.clear();
+        let scheme = self.config.stick_scheme;
+        self.batch(|state| state.do_recenter(scheme.route(Position::X), scheme.route(Position::Y)));
+    }
 
-        match event {
-            winit::event::DeviceEvent::MouseMotion { delta } => {
-                state.do_mouse_move(delta);
-                event_loop.set_control_flow(ControlFlow::wait_duration(Duration::from_millis(20)));
+    /// Sends the combined value of the held movement keys and the wheel nudge for a left stick axis
+    fn send_movement_axis(&mut self, position: Position) {
+        if self.config.diagonal_lock || self.config.left_stick_max.is_some() {
+            // Both axes depend on each other once the stick is looked at as a whole
+            let (mut x, mut y) = (
+                self.movement_value(Position::X),
+                self.movement_value(Position::Y),
+            );
+            if self.config.diagonal_lock {
+                (x, y) = diagonal(x, y);
             }
-            winit::event::DeviceEvent::Button {
-                button,
-                state: button_state,
-            } => {
-                state.do_mouse_button(button, button_state.is_pressed());
+            if let Some(max) = self.config.left_stick_max {
+                (x, y) = clamp_length(x, y, max);
             }
-            winit::event::DeviceEvent::Key(event) => {
-                if let PhysicalKey::Code(key) = event.physical_key {
-                    match key {
-                        KeyCode::Delete => {
-                            state.hide_mouse(false);
-                            event_loop.exit()
-                        }
-                        KeyCode::Backslash => {
-                            if event.state.is_pressed() {
-                                state.hide_mouse(!state.mouse_hiding());
-                            }
-                        }
-                        key => state.do_key(key, event.state.is_pressed()),
-                    }
-                }
+            self.output_movement_axis(Position::X, x);
+            self.output_movement_axis(Position::Y, y);
+        } else {
+            let value = self.movement_value(position);
+            self.output_movement_axis(position, value);
+        }
+    }
+
+    fn movement_value(&self, position: Position) -> i32 {
+        let mut value = self
+            .position_held_map
+            .get(&position)
+            .map_or(0, |position_helds| held_axis_value(position_helds));
+        if self
+            .config
+            .wheel_nudge
+            .as_ref()
+            .is_some_and(|nudge| nudge.axis.0 == position)
+        {
+            value += self.wheel_offset.round() as i32;
+        }
+
+        let inverted = match position {
+            Position::X => self.config.invert_left_x,
+            Position::Y => self.config.invert_left_y,
+            _ => false,
+        };
+        if inverted {
+            value = -value;
+        }
+
+        value
+    }
+
+    fn output_movement_axis(&mut self, position: Position, value: i32) {
+        let value = value.clamp(AXIS_MIN, AXIS_MAX);
+        if self.config.movement_ramp.enabled() {
+            self.movement_target.insert(position, value);
+            self.ramp_at
+                .get_or_insert_with(|| Instant::now() + RAMP_TICK);
+        } else {
+            let output = self.config.stick_scheme.route(position);
+            self.send(Absolute::Position(output), value);
+        }
+    }
+
+    /// Moves the left stick one step closer to where the held keys want it to be
+    fn step_movement_ramp(&mut self, now: Instant) {
+        let ramp = self.config.movement_ramp;
+        let mut ramping = false;
+        for position in [Position::X, Position::Y] {
+            let target = self.movement_target.get(&position).copied().unwrap_or(0) as f64;
+            let current = self.movement_current.entry(position).or_default();
+            if *current == target {
+                continue;
             }
-            _ => (),
+
+            // Heading back towards center uses the release time, and reversing goes through
+            // center first
+            let reversing = *current != 0. && target.signum() != current.signum();
+            let releasing = reversing || target.abs() < current.abs();
+            let goal = if reversing { 0. } else { target };
+            let ramp_ms = if releasing {
+                ramp.release_ms
+            } else {
+                ramp.attack_ms
+            };
+            let step = match ramp_ms {
+                0 => f64::INFINITY,
+                ms => AXIS_MAX as f64 * RAMP_TICK.as_millis() as f64 / ms as f64,
+            };
+
+            *current = if (goal - *current).abs() <= step {
+                goal
+            } else {
+                *current + step * (goal - *current).signum()
+            };
+            ramping |= *current != target;
+
+            let value = current.round() as i32;
+            let output = self.config.stick_scheme.route(position);
+            self.send(Absolute::Position(output), value);
         }
+
+        self.ramp_at = ramping.then(|| now + RAMP_TICK);
     }
-}
-fn main() {
-    let event_loop = EventLoop::new().unwrap();
 
-    event_loop.set_control_flow(ControlFlow::Wait);
+    fn do_mouse_wheel(&mut self, delta: MouseScrollDelta) {
+        let (nudge_axis, per_notch) = match &self.config.wheel_nudge {
+            Some(nudge) => (nudge.axis.0, nudge.per_notch),
+            None => return,
+        };
+
+        // Only line (notch) based scrolling is mapped, X11 reports the wheel like that
+        let notches = match delta {
+            MouseScrollDelta::LineDelta(_, y) => y as f64,
+            MouseScrollDelta::PixelDelta(_) => return,
+        };
+
+        self.wheel_offset =
+            (self.wheel_offset + notches * per_notch).clamp(AXIS_MIN as f64, AXIS_MAX as f64);
+        self.wheel_decay_at = Some(Instant::now() + WHEEL_DECAY_TICK);
+        self.send_movement_axis(nudge_axis);
+    }
+
+    fn decay_wheel_nudge(&mut self, now: Instant) {
+        let (nudge_axis, decay_ms) = match &self.config.wheel_nudge {
+            Some(nudge) => (nudge.axis.0, nudge.decay_ms),
+            None => return,
+        };
+
+        // decay_ms is how long a full deflection takes to get back to center
+        let step = AXIS_MAX as f64 * WHEEL_DECAY_TICK.as_millis() as f64 / decay_ms.max(1) as f64;
+        if self.wheel_offset.abs() <= step {
+            self.wheel_offset = 0.;
+            self.wheel_decay_at = None;
+        } else {
+            self.wheel_offset -= step * self.wheel_offset.signum();
+            self.wheel_decay_at = Some(now + WHEEL_DECAY_TICK);
+        }
+
+        self.send_movement_axis(nudge_axis);
+    }
+
+    fn do_mouse_button(&mut self, button: u32, pressed: bool) {
+        if self
+            .config
+            .button_mode
+            .as_ref()
+            .is_some_and(|mode| mode.button == button)
+        {
+            self.button_mode_held = pressed;
+            return;
+        }
+
+        if let Some(uinput_event) = mouse_button_to_controller_event(button) {
+            self.send(uinput_event, if pressed { 1 } else { 0 });
+        }
+    }
+
+    /// The stick settings in use, the `button_mode` ones while its button is held
+    fn stick(&self) -> &StickSettings {
+        match &self.config.button_mode {
+            Some(mode) if self.button_mode_held => &mode.stick,
+            _ => &self.config.stick,
+        }
+    }
+
+    /// The sensitivity in use. While the `button_mode` button is held the toggle scales the mode's
+    /// sensitivity by as much as it changes the normal one.
+    fn sensitivity(&self) -> f64 {
+        let base = self.config.stick.sensitivity;
+        let toggled = match &self.config.sensitivity_toggle {
+            Some(toggle) if self.alternate_sensitivity => toggle.alternate,
+            _ => base,
+        };
+        if self.button_mode_held {
+            self.stick().sensitivity * toggled / base
+        } else {
+            toggled
+        }
+    }
 
-    let mut app = App::default();
-    event_loop
-        .run_app(&mut app)
-        .expect("Failed to create window");
+    /// The live vertical scale. While the `button_mode` button is held it's the mode's one, moved
+    /// by as much as the hotkeys moved the normal one.
+    fn vertical_scale(&self) -> f64 {
+        if self.button_mode_held {
+            let (min, max) = VERTICAL_SCALE_RANGE.into_inner();
+            let offset = self.vertical_scale - self.config.stick.vertical_scale;
+            (self.stick().vertical_scale + offset).clamp(min, max)
+        } else {
+            self.vertical_scale
+        }
+    }
+
+    fn adjust_vertical_scale(&mut self, amount: f64) {
+        let (min, max) = VERTICAL_SCALE_RANGE.into_inner();
+        self.vertical_scale = (self.vertical_scale + amount).clamp(min, max);
+        eprintln!("Vertical scale: {:.2}", self.vertical_scale);
+    }
+
+    /// The live deadzone, offset the same way as `vertical_scale` while in the `button_mode`
+    fn effective_deadzone(&self) -> f64 {
+        if self.deadzone_off {
+            0.
+        } else if self.button_mode_held {
+            let offset = self.deadzone - self.config.stick.deadzone;
+            (self.stick().deadzone + offset).clamp(0., MAX_DEADZONE)
+        } else {
+            self.deadzone
+        }
+    }
+
+    fn adjust_deadzone(&mut self, amount: f64) {
+        self.deadzone = (self.deadzone + amount).clamp(0., MAX_DEADZONE);
+        eprintln!("Deadzone: {}", self.deadzone);
+    }
+
+    fn set_camera_lock(&mut self, locked: bool) {
+        if locked {
+            let (axis_x, axis_y) = self.mouse_axes();
+            let value_of = |position| self.axis_values.get(&position).copied().unwrap_or(0);
+            self.camera_lock = Some((value_of(axis_x), value_of(axis_y)));
+            self.camera_lock_motion = (0., 0.);
+            self.recenter_at = None;
+            self.update_direction_binds((0., 0.));
+        } else if self.camera_lock.take().is_some() {
+            let motion = std::mem::take(&mut self.camera_lock_motion);
+            if motion != (0., 0.) {
+                // Catch up on what the mouse did while locked, then recenter as usual
+                self.do_mouse_move(motion);
+            } else {
+                // Go back to normal mapping, which recenters unless the mouse is moving
+                self.recenter_at = Some(Instant::now() + self.stick().recenter_timeout());
+            }
+        }
+    }
+
+    fn do_mouse_move(&mut self, delta: (f64, f64)) {
+        // Movement from while paused can arrive bundled into the first motion after it
+        if self.active && std::mem::take(&mut self.unpaused) {
+            return;
+        }
+
+        if self.camera_lock.is_some() {
+            if self.config.camera_lock_motion == CameraLockMotion::Accumulate {
+                self.camera_lock_motion.0 += delta.0;
+                self.camera_lock_motion.1 += delta.1;
+            }
+            return;
+        }
+
+        // A motion landing right on a recenter would jump the stick straight back out
+        if let Some(at) = self.recentered_at.take() {
+            if self
+                .stick()
+                .recenter_echo()
+                .is_some_and(|echo| at.elapsed() <= echo)
+            {
+                self.smoothed = (0., 0.);
+                return;
+            }
+        }
+
+        let previous = self.mouse_delta;
+        self.update_direction_binds(delta);
+        self.update_speed_trigger(delta);
+
+        let stick = stick_from_motion(delta, self.sensitivity(), self.vertical_scale());
+        let (mut stick_x, mut stick_y) =
+            apply_gain(stick, self.stick().gain_x, self.stick().gain_y);
+
+        if self.stick().smoothing > 0. {
+            let speed = delta.0.hypot(delta.1);
+            let smoothing = match &self.stick().smoothing_by_speed {
+                Some(by_speed) => self.stick().smoothing * by_speed.scale(speed),
+                None => self.stick().smoothing,
+            };
+            // An axis turning around sharply starts over from the new motion instead of easing
+            // out of the old one
+            let threshold = self.stick().reversal_reset;
+            let reversed = |previous: f64, delta: f64| {
+                threshold > 0. && previous * delta < 0. && delta.abs() >= threshold
+            };
+            if reversed(previous.0, delta.0) {
+                self.smoothed.0 = stick_x;
+            }
+            if reversed(previous.1, delta.1) {
+                self.smoothed.1 = stick_y;
+            }
+            self.smoothed.0 = self.smoothed.0 * smoothing + stick_x * (1. - smoothing);
+            self.smoothed.1 = self.smoothed.1 * smoothing + stick_y * (1. - smoothing);
+            (stick_x, stick_y) = self.smoothed;
+        }
+
+        if stick_x.hypot(stick_y) < self.effective_deadzone() {
+            (stick_x, stick_y) = (0., 0.);
+        }
+
+        // Only motions get boosted, centered and recentering values are sent as they are so the
+        // game reads them as neutral
+        let anti_deadzone = self.stick().anti_deadzone;
+        let magnitude = stick_x.hypot(stick_y);
+        if anti_deadzone > 0. && magnitude > 0. {
+            let boosted = anti_deadzone + magnitude * (1. - anti_deadzone / AXIS_MAX as f64);
+            stick_x *= boosted / magnitude;
+            stick_y *= boosted / magnitude;
+        }
+
+        self.send_mouse_axes(stick_x, stick_y);
+        let magnitude = stick_x.hypot(stick_y);
+        self.holding_small = magnitude > 0. && magnitude < self.stick().recenter_hold_below;
+        self.recenter_at = Some(Instant::now() + self.stick().recenter_timeout());
+    }
+
+    fn update_speed_trigger(&mut self, delta: (f64, f64)) {
+        let full_speed = match &self.config.speed_trigger {
+            Some(speed_trigger) => speed_trigger.full_speed,
+            None => return,
+        };
+
+        self.speed_level = (delta.0.hypot(delta.1) / full_speed).min(1.);
+        // Only starts decaying once the mouse stops, like the stick recentering
+        self.speed_decay_at = Some(Instant::now() + self.stick().recenter_timeout());
+        self.send_speed_trigger();
+    }
+
+    fn decay_speed_trigger(&mut self, now: Instant) {
+        let decay_ms = match &self.config.speed_trigger {
+            Some(speed_trigger) => speed_trigger.decay_ms,
+            None => return,
+        };
+
+        let step = SPEED_DECAY_TICK.as_millis() as f64 / decay_ms.max(1) as f64;
+        if self.speed_level <= step {
+            self.speed_level = 0.;
+            self.speed_decay_at = None;
+        } else {
+            self.speed_level -= step;
+            self.speed_decay_at = Some(now + SPEED_DECAY_TICK);
+        }
+        self.send_speed_trigger();
+    }
+
+    fn send_speed_trigger(&mut self) {
+        if let Some(speed_trigger) = &self.config.speed_trigger {
+            let value = (self.speed_level * device::TRIGGER_MAX as f64).round() as i32;
+            self.send(Absolute::Position(speed_trigger.axis.0), value);
+        }
+    }
+
+    /// Eases the smoothed right stick back to center, pinning it there once it's close enough
+    fn step_smoothed_recenter(&mut self, now: Instant) {
+        let smoothing = self.stick().smoothing;
+        self.smoothed.0 *= smoothing;
+        self.smoothed.1 *= smoothing;
+
+        let band = self.stick().center_hold;
+        if self.smoothed.0.abs() <= band && self.smoothed.1.abs() <= band {
+            // Clear the filter too so it can't carry the stick back out of center
+            self.smoothed = (0., 0.);
+            self.recenter_mouse_axes();
+            self.recenter_at = None;
+        } else {
+            self.send_mouse_axes(self.smoothed.0, self.smoothed.1);
+            self.recenter_at = Some(now + SMOOTHING_TICK);
+        }
+    }
+
+    /// Presses or releases the direction binds depending on which way the mouse is moving
+    fn update_direction_binds(&mut self, delta: (f64, f64)) {
+        self.mouse_delta = delta;
+        let direction = Direction::of_motion(delta);
+        for i in 0..self.config.direction_binds.len() {
+            let bind = &self.config.direction_binds[i];
+            let amount = bind.direction.amount(delta);
+            // Arming and disarming at different amounts stops the button chattering when the
+            // motion hovers around a threshold
+            let moving = if self.direction_binds_active[i] {
+                amount > bind.disarm
+            } else {
+                direction == Some(bind.direction) && amount > bind.arm
+            };
+            let active = moving
+                && bind
+                    .modifier
+                    .is_none_or(|modifier| self.held_keys.contains(&modifier.0));
+            let button = bind.button.0;
+            if active != self.direction_binds_active[i] {
+                self.direction_binds_active[i] = active;
+                self.send(button, if active { 1 } else { 0 });
+            }
+        }
+    }
+
+    fn hold_look_key(&mut self, key: KeyCode, pressed: bool) {
+        self.look_keys_held.retain(|held| *held != key);
+        if pressed {
+            self.look_keys_held.push(key);
+        }
+
+        // The camera lock keeps the stick where it is, and the keys get picked up once it's let go
+        if self.camera_lock.is_none() {
+            self.send_mouse_axes(self.mouse_stick.0, self.mouse_stick.1);
+        }
+    }
+
+    /// How far the held look keys push the mouse driven axes. Opposite keys cancel out and keys
+    /// going the same way don't add up, like the movement keys.
+    fn look_offset(&self) -> (f64, f64) {
+        let look_keys = &self.config.look_keys;
+        let held = |direction| {
+            self.look_keys_held
+                .iter()
+                .any(|key| look_keys.keys.get(&Key(*key)) == Some(&direction))
+        };
+        let push = |negative, positive| {
+            (held(positive) as i32 - held(negative) as i32) as f64 * look_keys.magnitude
+        };
+        (
+            push(Direction::Left, Direction::Right),
+            push(Direction::Up, Direction::Down),
+        )
+    }
+
+    fn mouse_axes(&self) -> (Position, Position) {
+        let scheme = self.config.stick_scheme;
+        (
+            scheme.route(self.config.mouse_x_axis.0),
+            scheme.route(self.config.mouse_y_axis.0),
+        )
+    }
+
+    /// Sends right stick style values to the axes the mouse drives, with the look keys added on
+    fn send_mouse_axes(&mut self, stick_x: f64, stick_y: f64) {
+        self.mouse_stick = (stick_x, stick_y);
+        if self.mouse_stick != (0., 0.) {
+            self.drift_flush_at = None;
+        } else if self.drift_flush_at.is_none() {
+            self.drift_flush_at = self
+                .config
+                .drift_flush
+                .as_ref()
+                .map(|drift_flush| Instant::now() + drift_flush.idle());
+        }
+        let (look_x, look_y) = self.look_offset();
+        let clamp = |value: f64| value.clamp(AXIS_MIN as f64, AXIS_MAX as f64);
+        let (stick_x, stick_y) = (clamp(stick_x + look_x), clamp(stick_y + look_y));
+
+        let (axis_x, axis_y) = self.mouse_axes();
+        let rounding = self.stick().rounding;
+        self.send(
+            Absolute::Position(axis_x),
+            axis_value(axis_x, stick_x, rounding),
+        );
+        self.send(
+            Absolute::Position(axis_y),
+            axis_value(axis_y, stick_y, rounding),
+        );
+    }
+
+    /// Takes the mouse out of the axes it drives, leaving them where the look keys push them
+    fn recenter_mouse_axes(&mut self) {
+        self.send_mouse_axes(0., 0.);
+        self.recentered_at = Some(Instant::now());
+    }
+
+    /// Sends the centered mouse axes again, as separate reports, so a game that latched a tiny
+    /// value before the recenter lets go of it
+    fn flush_drift(&mut self) {
+        self.drift_flush_at = None;
+        let count = match &self.config.drift_flush {
+            Some(drift_flush) => drift_flush.count,
+            None => return,
+        };
+        // The look keys hold the stick out on purpose
+        if self.look_offset() != (0., 0.) {
+            return;
+        }
+
+        let (axis_x, axis_y) = self.mouse_axes();
+        for _ in 0..count {
+            self.batch(|state| {
+                state.send(Absolute::Position(axis_x), 0);
+                state.send(Absolute::Position(axis_y), 0);
+            });
+        }
+    }
+
+    fn do_recenter(&mut self, pos1: Position, pos2: Position) {
+        self.send(Absolute::Position(pos1), 0);
+        self.send(Absolute::Position(pos2), 0);
+    }
+
+    /// Centers the sticks and releases the triggers
+    fn recenter_all(&mut self) {
+        self.recentered_at = Some(Instant::now());
+        self.batch(|state| {
+            for position in device::STICK_AXES.into_iter().chain(device::TRIGGER_AXES) {
+                state.send(Absolute::Position(position), 0);
+            }
+        });
+    }
+
+    fn update_timers(&mut self, now: Instant) {
+        if self.startup_at.is_some_and(|at| at <= now) {
+            self.startup_at = None;
+            self.recenter_all();
+        }
+
+        if self.flush_pending_at.is_some_and(|at| at <= now) {
+            self.flush_pending_events();
+        }
+
+        if self.xbanish_check_at.is_some_and(|at| at <= now) {
+            self.check_xbanish(now);
+        }
+
+        if self.recenter_at.is_some_and(|at| at <= now) {
+            if self.holding_small {
+                // Kept for fine aiming, the next motion takes over from it unless the hold runs
+                // out first
+                self.holding_small = false;
+                self.recenter_at = self.stick().recenter_hold().map(|hold| now + hold);
+            } else if self.stick().smoothing > 0. {
+                self.step_smoothed_recenter(now);
+            } else {
+                self.recenter_at = None;
+                self.recenter_mouse_axes();
+            }
+            self.update_direction_binds((0., 0.));
+        }
+
+        if self.drift_flush_at.is_some_and(|at| at <= now) {
+            self.flush_drift();
+        }
+
+        if self.watchdog_at.is_some_and(|at| at <= now) {
+            self.watchdog_at = None;
+            eprintln!("No input received for a while, releasing all inputs");
+            self.reset_all();
+        }
+
+        if self.wheel_decay_at.is_some_and(|at| at <= now) {
+            self.decay_wheel_nudge(now);
+        }
+
+        if self.speed_decay_at.is_some_and(|at| at <= now) {
+            self.decay_speed_trigger(now);
+        }
+
+        let due_releases: Vec<_> = self
+            .pending_releases
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(event, _)| *event)
+            .collect();
+        for event in due_releases {
+            self.pending_releases.remove(&event);
+            self.send_event(event, 0);
+        }
+
+        let due_turbos: Vec<_> = self
+            .turbos
+            .iter()
+            .filter(|(_, turbo)| turbo.next_at <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in due_turbos {
+            self.step_turbo(key, now);
+        }
+
+        if self.movement_stop_at.is_some_and(|at| at <= now) {
+            self.movement_stop_at = None;
+            self.send_movement_axis(Position::X);
+            self.send_movement_axis(Position::Y);
+        }
+
+        if self.ramp_at.is_some_and(|at| at <= now) {
+            self.step_movement_ramp(now);
+        }
+
+        if self.neutral_hold_at.is_some_and(|at| at <= now) {
+            let axis_values: Vec<_> = self.axis_values.iter().map(|(p, v)| (*p, *v)).collect();
+            for (position, value) in axis_values {
+                self.send(Absolute::Position(position), value);
+            }
+            self.neutral_hold_at = self.config.neutral_hold_period().map(|period| now + period);
+        }
+    }
+
+    fn feed_watchdog(&mut self) {
+        self.watchdog_at = self
+            .config
+            .watchdog_timeout()
+            .map(|timeout| Instant::now() + timeout);
+    }
+
+    /// Switches to the next profile of the cycle. Everything is reset first so nothing pressed
+    /// under the old profile's keymap stays stuck, and presses coming too soon after a switch are
+    /// ignored so a burst of them steps through the profiles one clean switch at a time.
+    fn cycle_profile(&mut self) {
+        let debounce = self.config.profile_switch_debounce();
+        if self
+            .profile_switched_at
+            .is_some_and(|at| at.elapsed() < debounce)
+        {
+            return;
+        }
+        self.profile_switched_at = Some(Instant::now());
+
+        let index = self
+            .profile_index
+            .map_or(0, |index| (index + 1) % self.profile_cycle.len());
+        self.reset_all();
+        let (name, config) = &self.profile_cycle[index];
+        eprintln!("Profile: {name}");
+        self.config = Rc::clone(config);
+        self.profile_index = Some(index);
+
+        // The live tunables start over from the new profile's settings
+        self.deadzone = self.config.stick.deadzone;
+        self.vertical_scale = self.config.stick.vertical_scale;
+        self.alternate_sensitivity = false;
+        self.direction_binds_active = vec![false; self.config.direction_binds.len()];
+        self.neutral_hold_at = self
+            .config
+            .neutral_hold_period()
+            .map(|period| Instant::now() + period);
+    }
+
+    /// Appends the config in use to the config file as a new profile, with the values changed by
+    /// hotkeys while running in place of the configured ones. The sensitivity toggle and button
+    /// mode only last while running, so the base settings are saved whatever their state.
+    fn save_snapshot(&self) {
+        let path = match &self.snapshot_path {
+            Some(path) => path,
+            None => {
+                eprintln!("No config file to save the profile to");
+                return;
+            }
+        };
+
+        let saved = toml::Table::try_from(&*self.config)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut profile| {
+                if let Some(toml::Value::Table(stick)) = profile.get_mut("stick") {
+                    stick.insert(
+                        "sensitivity".to_string(),
+                        self.config.stick.sensitivity.into(),
+                    );
+                    stick.insert("deadzone".to_string(), self.deadzone.into());
+                    stick.insert("vertical_scale".to_string(), self.vertical_scale.into());
+                }
+                config::append_profile(path, profile)
+            });
+        match saved {
+            Ok(name) => eprintln!("Saved as profile `{name}` in {}", path.display()),
+            Err(err) => eprintln!("Failed to save the profile: {err:#}"),
+        }
+    }
+
+    /// Puts the controller back into the neutral state it starts in: every axis centered, every
+    /// button released and everything held, toggled, pulsing or easing forgotten. Physical keys
+    /// still held down only take effect again once they're pressed again.
+    fn reset_all(&mut self) {
+        self.pending_events.clear();
+        self.flush_pending_at = None;
+        // Releases go out right away, with held back ones included since they're still held
+        self.pressed_at.clear();
+        self.pending_releases.clear();
+        self.layer_active = false;
+        self.position_held_map.clear();
+        self.toggled_keys.clear();
+        self.cycle_positions.clear();
+        self.turbos.clear();
+        self.direction_binds_active.fill(false);
+        self.mouse_delta = (0., 0.);
+        self.mouse_stick = (0., 0.);
+        self.look_keys_held.clear();
+        self.unmapped_held.clear();
+        self.movement_target.clear();
+        self.movement_current.clear();
+        self.ramp_at = None;
+        self.movement_stop_at = None;
+        self.camera_lock = None;
+        self.camera_lock_motion = (0., 0.);
+        self.wheel_offset = 0.;
+        self.wheel_decay_at = None;
+        self.speed_level = 0.;
+        self.speed_decay_at = None;
+        self.drift_flush_at = None;
+        self.recenter_at = None;
+        self.holding_small = false;
+        self.deadzone_off = false;
+        self.button_mode_held = false;
+        self.smoothed = (0., 0.);
+
+        self.batch(|state| {
+            let held_buttons: Vec<_> = state.held_buttons.iter().copied().collect();
+            for button in held_buttons {
+                state.send(button, 0);
+            }
+            state.recenter_all();
+        });
+    }
+
+    fn next_timer(&self) -> Option<Instant> {
+        [
+            self.startup_at,
+            self.xbanish_check_at,
+            self.recenter_at,
+            self.neutral_hold_at,
+            self.flush_pending_at,
+            self.wheel_decay_at,
+            self.speed_decay_at,
+            self.drift_flush_at,
+            self.watchdog_at,
+            self.ramp_at,
+            self.movement_stop_at,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(self.turbos.values().map(|turbo| turbo.next_at))
+        .chain(self.pending_releases.values().copied())
+        .min()
+    }
+
+    /// Switches between sending input with the cursor hidden and pausing with it shown
+    fn toggle_active(&mut self) {
+        if self.active {
+            // Reset while sending still works so nothing stays pushed while paused
+            match self.config.pause_release {
+                PauseRelease::Mouse => {
+                    self.camera_lock = None;
+                    self.smoothed = (0., 0.);
+                    self.recenter_at = None;
+                    self.update_direction_binds((0., 0.));
+                    self.recenter_mouse_axes();
+                }
+                PauseRelease::All => self.reset_all(),
+                PauseRelease::Nothing => (),
+            }
+            self.hide_mouse(false);
+        } else {
+            self.hide_mouse(true);
+            self.unpaused = self.config.unpause_baseline;
+            // Keys could have been pressed or released while paused
+            self.send_movement_axis(Position::X);
+            self.send_movement_axis(Position::Y);
+        }
+    }
+
+    fn hide_mouse(&mut self, hide: bool) {
+        self.active = hide;
+        if self.headless {
+            return;
+        }
+
+        if hide {
+            self.xbanish_proc = spawn_xbanish();
+            self.xbanish_restarts = 0;
+            self.xbanish_check_at = self
+                .config
+                .xbanish_check_period()
+                .map(|period| Instant::now() + period);
+        } else {
+            self.xbanish_check_at = None;
+            if let Some(mut process) = self.xbanish_proc.take() {
+                process.kill().unwrap();
+                process.wait().unwrap();
+            }
+        }
+    }
+
+    /// Restarts xbanish if it exited while the cursor is meant to be hidden. Each restart in a
+    /// row doubles the time until the next check, so one that keeps dying isn't respawned
+    /// over and over.
+    fn check_xbanish(&mut self, now: Instant) {
+        let period = match self.config.xbanish_check_period() {
+            Some(period) => period,
+            None => return,
+        };
+        let status = match &mut self.xbanish_proc {
+            Some(process) => process.try_wait(),
+            // It never started, so there's nothing to restart
+            None => {
+                self.xbanish_check_at = None;
+                return;
+            }
+        };
+
+        match status {
+            Ok(None) => self.xbanish_restarts = 0,
+            Ok(Some(status)) => {
+                eprintln!("xbanish exited ({status}), restarting it");
+                self.xbanish_proc = spawn_xbanish();
+                self.xbanish_restarts += 1;
+            }
+            Err(err) => eprintln!("Failed to check on xbanish: {err}"),
+        }
+
+        let backoff = period * 2_u32.pow(self.xbanish_restarts.min(6));
+        self.xbanish_check_at = self
+            .xbanish_proc
+            .is_some()
+            .then(|| now + backoff.min(XBANISH_MAX_BACKOFF));
+    }
+
+    /// Handles a key press or release from the keyboard, returning false once the exit key is
+    /// pressed
+    fn key_event(&mut self, key: KeyCode, pressed: bool) -> bool {
+        if !self.key_changed(key, pressed) {
+            return true;
+        }
+
+        match key {
+            KeyCode::Delete => {
+                self.hide_mouse(false);
+                self.report_send_stats();
+                return false;
+            }
+            _ if self.starting() => (),
+            KeyCode::Backslash => {
+                if pressed {
+                    self.toggle_active();
+                }
+            }
+            key => self.do_key(key, pressed),
+        }
+        true
+    }
+
+    /// Whether the startup delay is still running, with all input and output held back
+    fn starting(&self) -> bool {
+        self.startup_at.is_some()
+    }
+
+    fn report_send_stats(&self) {
+        let SendStats { dropped, coalesced } = self.send_stats;
+        if dropped > 0 || coalesced > 0 {
+            eprintln!(
+                "Device buffer was full: {dropped} events dropped, {coalesced} events coalesced"
+            );
+        }
+    }
+}
+
+/// Combines what the held keys push an axis by. Keys pushing the same way don't add up, the
+/// furthest one wins, while keys pushing opposite ways cancel out.
+fn held_axis_value(position_helds: &[(KeyCode, i32)]) -> i32 {
+    let values = position_helds.iter().map(|(_, value)| *value);
+    let positive = values.clone().filter(|value| *value > 0).max().unwrap_or(0);
+    let negative = values.filter(|value| *value < 0).min().unwrap_or(0);
+    positive + negative
+}
+
+/// Snaps a left stick vector to the closest diagonal, keeping its length up to full deflection.
+/// A single direction sits right between two diagonals so it's turned clockwise onto the next one.
+fn diagonal(x: i32, y: i32) -> (i32, i32) {
+    if x == 0 && y == 0 {
+        return (0, 0);
+    }
+
+    let sign_x = if x != 0 { x.signum() } else { -y.signum() };
+    let sign_y = if y != 0 { y.signum() } else { x.signum() };
+    let length = (x as f64).hypot(y as f64).min(AXIS_MAX as f64);
+    let component = (length / std::f64::consts::SQRT_2).round() as i32;
+    (sign_x * component, sign_y * component)
+}
+
+/// Shortens a stick vector to at most `max` long, keeping its direction
+fn clamp_length(x: i32, y: i32, max: f64) -> (i32, i32) {
+    let length = (x as f64).hypot(y as f64);
+    if length <= max {
+        return (x, y);
+    }
+
+    let scale = max / length;
+    let exact = (x as f64 * scale, y as f64 * scale);
+    let (mut x, mut y) = (exact.0.round(), exact.1.round());
+    // Rounding to nearest can land just past `max`, so whichever axis was rounded out the most
+    // is rounded toward center instead, then the other one if that's still not enough. Rounding
+    // both toward center right away could leave a short stick not moving at all.
+    if x.hypot(y) > max {
+        if x.abs() - exact.0.abs() >= y.abs() - exact.1.abs() {
+            x = exact.0.trunc();
+        } else {
+            y = exact.1.trunc();
+        }
+    }
+    if x.hypot(y) > max {
+        (x, y) = (exact.0.trunc(), exact.1.trunc());
+    }
+    (x as i32, y as i32)
+}
+
+/// Starts xbanish hiding the cursor for as long as it runs, moved out of the way into the bottom
+/// right corner, or logs why it couldn't
+fn spawn_xbanish() -> Option<std::process::Child> {
+    Command::new("xbanish")
+        .args(["-a", "-i", "mod4", "-m", "se"])
+        .spawn()
+        .inspect_err(|err| eprintln!("Failed to run xbanish: {err}"))
+        .ok()
+}
+
+/// Runs a `startup_command` or `shutdown_command` through the shell, logging it if it fails.
+/// Startup ones are waited for on their own thread since they can keep running, e.g. a game
+/// being launched, while shutdown ones are waited for right away as the process exits after.
+fn run_command(command: &str, background: bool) {
+    let mut child = match Command::new("sh").args(["-c", command]).spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            eprintln!("Failed to run `{command}`: {err}");
+            return;
+        }
+    };
+
+    let command = command.to_string();
+    let mut wait = move || match child.wait() {
+        Ok(status) if !status.success() => eprintln!("`{command}` failed with {status}"),
+        Ok(_) => (),
+        Err(err) => eprintln!("Failed to wait for `{command}`: {err}"),
+    };
+    if background {
+        std::thread::spawn(wait);
+    } else {
+        wait();
+    }
+}
+
+/// Converts a right stick style value into what `position` takes. Triggers are pushed by the
+/// stick going either way from center.
+fn axis_value(position: Position, stick: f64, rounding: Rounding) -> i32 {
+    if device::is_trigger(position) {
+        let trigger = stick.abs() * device::TRIGGER_MAX as f64 / AXIS_MAX as f64;
+        rounding.apply(trigger).min(device::TRIGGER_MAX)
+    } else {
+        rounding.apply(stick)
+    }
+}
+
+/// Maps a mouse motion to right stick values, before smoothing and the deadzone
+fn stick_from_motion(delta: (f64, f64), sensitivity: f64, vertical_scale: f64) -> (f64, f64) {
+    let range = 10. / sensitivity;
+    // Mapped around exactly 0 so a motion along one axis doesn't round into a nudge on the other
+    let stick_x = map_range(delta.0, -range, range, -127.5, 127.5);
+    let stick_y = map_range(delta.1, -range, range, -127.5, 127.5) * vertical_scale;
+
+    (
+        stick_x.signum() * stick_x.abs().sqrt(),
+        stick_y.signum() * stick_y.abs().sqrt(),
+    )
+}
+
+/// Scales each stick axis by its output gain, keeping it within the stick's range
+fn apply_gain(stick: (f64, f64), gain_x: f64, gain_y: f64) -> (f64, f64) {
+    let clamp = |value: f64| value.clamp(AXIS_MIN as f64, AXIS_MAX as f64);
+    (clamp(stick.0 * gain_x), clamp(stick.1 * gain_y))
+}
+
+fn is_hotkey(hotkey: Option<Key>, key: KeyCode) -> bool {
+    hotkey.is_some_and(|hotkey| hotkey.0 == key)
+}
+
+fn map_range(x: f64, in_min: f64, in_max: f64, out_min: f64, out_max: f64) -> f64 {
+    (x - in_min) * (out_max - out_min) / (in_max - in_min) + out_min
+}
+
+struct App {
+    args: Args,
+    config: Option<Config>,
+    state: Option<AppState>,
+    // Set when starting up failed, so the process exits with an error
+    failed: bool,
+}
+
+impl ApplicationHandler for App {
+    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+        let config = match self.config.take() {
+            Some(config) => config,
+            None => return,
+        };
+
+        let mut state = match AppState::new(config, &self.args) {
+            Ok(state) => state,
+            Err(err) => {
+                eprintln!("Failed to start: {err:#}");
+                self.failed = true;
+                event_loop.exit();
+                return;
+            }
+        };
+        if state.config.start_hidden && !self.args.start_visible {
+            state.hide_mouse(true);
+        }
+        // Center joystick, once the startup delay is over if there is one
+        match state.config.startup_delay() {
+            Some(delay) => state.startup_at = Some(Instant::now() + delay),
+            None => state.recenter_all(),
+        }
+        if let Some(command) = &state.config.startup_command {
+            run_command(command, true);
+        }
+        self.state = Some(state);
+    }
+
+    fn exiting(&mut self, _: &ActiveEventLoop) {
+        let command = self
+            .state
+            .as_ref()
+            .and_then(|state| state.config.shutdown_command.as_ref());
+        if let Some(command) = command {
+            run_command(command, false);
+        }
+    }
+
+    fn window_event(&mut self, _: &ActiveEventLoop, window_id: WindowId, event: WindowEvent) {
+        if self.args.log_events {
+            eprintln!("Window event ({window_id:?}): {event:?}");
+        }
+    }
+
+    fn new_events(&mut self, _: &ActiveEventLoop, _: winit::event::StartCause) {
+        if let Some(state) = self.state.as_mut() {
+            state.update_timers(Instant::now());
+        }
+    }
+
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        if let Some(state) = self.state.as_ref() {
+            event_loop.set_control_flow(match state.next_timer() {
+                Some(at) => ControlFlow::WaitUntil(at),
+                None => ControlFlow::Wait,
+            });
+        }
+    }
+
+    fn device_event(
+        &mut self,
+        event_loop: &ActiveEventLoop,
+        device_id: winit::event::DeviceId,
+        event: winit::event::DeviceEvent,
+    ) {
+        if self.args.log_events {
+            eprintln!("Device event ({device_id:?}): {event:?}");
+        }
+
+        let state = match self.state.as_mut() {
+            Some(state) => state,
+            None => return,
+        };
+
+        state.feed_watchdog();
+        match event {
+            // Only the keyboard goes through during the startup delay, so it can still exit
+            winit::event::DeviceEvent::MouseMotion { .. }
+            | winit::event::DeviceEvent::Button { .. }
+            | winit::event::DeviceEvent::MouseWheel { .. }
+                if state.starting() => {}
+            winit::event::DeviceEvent::MouseMotion { delta } => {
+                let arrived = Instant::now();
+                state.do_mouse_move(delta);
+                if let Some(timing) = state.timing.as_mut() {
+                    timing.record_motion(arrived, arrived.elapsed());
+                }
+            }
+            winit::event::DeviceEvent::Button {
+                button,
+                state: button_state,
+            } => {
+                state.do_mouse_button(button, button_state.is_pressed());
+            }
+            winit::event::DeviceEvent::MouseWheel { delta } => {
+                state.do_mouse_wheel(delta);
+            }
+            winit::event::DeviceEvent::Key(event) => {
+                if let PhysicalKey::Code(key) = event.physical_key {
+                    if !state.key_event(key, event.state.is_pressed()) {
+                        event_loop.exit()
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+fn main() -> ExitCode {
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::from(2);
+        }
+    };
+    if args.list_keys {
+        names::print_names();
+        return ExitCode::SUCCESS;
+    }
+
+    if args.no_sync {
+        eprintln!("Warning: --no-sync is enabled, input may appear laggy or batched");
+    }
+
+    let config = match Config::load(&args.config_paths, args.profile.as_deref()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Failed to load config: {err:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if args.print_config {
+        match toml::to_string(&config) {
+            Ok(text) => print!("{text}"),
+            Err(err) => {
+                eprintln!("Failed to print config: {err}");
+                return ExitCode::FAILURE;
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    for warning in config.warnings() {
+        eprintln!("Warning: {warning}");
+    }
+
+    if let Some(path) = &args.script {
+        if let Err(err) = script::run(path, config, &args) {
+            eprintln!("Script failed: {err:#}");
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let event_loop = match EventLoop::new() {
+        Ok(event_loop) => event_loop,
+        Err(err) => {
+            eprintln!("Failed to connect to the display server: {err}");
+            eprintln!("Input is captured through X11 so a running X display is required (is DISPLAY set?)");
+            eprintln!("To try a config without one, feed inputs from a file with --script FILE --mock-device");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    event_loop.set_control_flow(ControlFlow::Wait);
+
+    if args.calibrate {
+        if let Err(err) = calibrate::run(
+            event_loop,
+            &config.stick,
+            config::save_path(&args.config_paths),
+        ) {
+            eprintln!("Calibration failed: {err:#}");
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let mut app = App {
+        args,
+        config: Some(config),
+        state: None,
+        failed: false,
+    };
+    if let Err(err) = event_loop.run_app(&mut app) {
+        eprintln!("Event loop exited with an error: {err}");
+        return ExitCode::FAILURE;
+    }
+    if app.failed {
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device::{Output, Recorded};
+    use std::sync::{Arc, Mutex};
+
+    /// A state that's sending input to a recording device, with the config from `text`
+    fn state(text: &str) -> AppState {
+        let config = Config::from_table(text.parse().unwrap(), None, "test.toml").unwrap();
+        let args = Args {
+            mock_device: true,
+            ..Args::default()
+        };
+        let mut state = AppState::new(config, &args).unwrap();
+        state.device = Device::Recording(Default::default());
+        state.headless = true;
+        state.hide_mouse(true);
+        state
+    }
+
+    /// The recording device's own record of what it was sent
+    fn recorded(state: &AppState) -> Arc<Mutex<Recorded>> {
+        match &state.device {
+            Device::Recording(recorded) => Arc::clone(recorded),
+            _ => unreachable!("tests record what's sent"),
+        }
+    }
+
+    /// Everything sent since the last call, written the way `--mock-device` prints it
+    fn sent(state: &mut AppState) -> Vec<String> {
+        let outputs = std::mem::take(&mut recorded(state).lock().unwrap().outputs);
+        written(outputs)
+    }
+
+    fn written(outputs: Vec<Output>) -> Vec<String> {
+        outputs
+            .into_iter()
+            .map(|output| match output {
+                Output::Event(event, value) => format!("{} {value}", names::event_name(event)),
+                Output::Sync => "SYN".to_string(),
+            })
+            .collect()
+    }
+
+    /// The values sent to `axis`, in order
+    fn axis_values(sent: &[String], axis: &str) -> Vec<i32> {
+        sent.iter()
+            .filter_map(|line| line.split_once(' '))
+            .filter(|(name, _)| *name == axis)
+            .map(|(_, value)| value.parse().unwrap())
+            .collect()
+    }
+
+    /// Fires the timers every smoothing tick for `duration` from `start`
+    fn run_timers(state: &mut AppState, start: Instant, duration: Duration) {
+        let mut now = start;
+        while now <= start + duration {
+            state.update_timers(now);
+            now += SMOOTHING_TICK;
+        }
+    }
+
+    #[test]
+    fn repeated_key_downs_are_one_press() {
+        let mut state = state("");
+        state.key_event(KeyCode::KeyC, true);
+        state.key_event(KeyCode::KeyC, true);
+        state.key_event(KeyCode::KeyC, false);
+        assert_eq!(sent(&mut state), ["B 1", "SYN", "B 0", "SYN"]);
+    }
+
+    #[test]
+    fn repeated_key_downs_flip_a_toggle_once() {
+        let mut state = state(
+            r#"
+            [bindings]
+            KeyP = { button = "A", toggle = true }
+            "#,
+        );
+        state.key_event(KeyCode::KeyP, true);
+        state.key_event(KeyCode::KeyP, true);
+        state.key_event(KeyCode::KeyP, false);
+        assert_eq!(sent(&mut state), ["A 1", "SYN"]);
+    }
+
+    #[test]
+    fn wasd_snaps_to_diagonals() {
+        let cases = [
+            ("", (0, 0)),
+            // Single keys turn clockwise onto the next diagonal
+            ("W", (90, -90)),
+            ("D", (90, 90)),
+            ("S", (-90, 90)),
+            ("A", (-90, -90)),
+            // Two keys are longer than one, up to full deflection
+            ("WD", (91, -91)),
+            ("SD", (91, 91)),
+            ("AS", (-91, 91)),
+            ("WA", (-91, -91)),
+            // Opposite keys cancel, leaving whatever else is held
+            ("WS", (0, 0)),
+            ("AD", (0, 0)),
+            ("WASD", (0, 0)),
+            ("WAD", (90, -90)),
+            ("WSD", (90, 90)),
+        ];
+        for (keys, expected) in cases {
+            let (mut x_helds, mut y_helds) = (Vec::new(), Vec::new());
+            for key in keys.chars() {
+                match key {
+                    'W' => y_helds.push((KeyCode::KeyW, -127)),
+                    'A' => x_helds.push((KeyCode::KeyA, -127)),
+                    'S' => y_helds.push((KeyCode::KeyS, 127)),
+                    'D' => x_helds.push((KeyCode::KeyD, 127)),
+                    _ => unreachable!(),
+                }
+            }
+            let (x, y) = (held_axis_value(&x_helds), held_axis_value(&y_helds));
+            assert_eq!(diagonal(x, y), expected, "holding `{keys}`");
+        }
+    }
+
+    #[test]
+    fn keys_pushing_the_same_way_dont_add_up() {
+        let helds = [(KeyCode::KeyW, -127), (KeyCode::KeyZ, -60)];
+        assert_eq!(held_axis_value(&helds), -127);
+        let helds = [
+            (KeyCode::KeyW, -127),
+            (KeyCode::KeyZ, -60),
+            (KeyCode::KeyS, 100),
+        ];
+        assert_eq!(held_axis_value(&helds), -27);
+    }
+
+    #[test]
+    fn clamp_length_keeps_within_max() {
+        // W+D reaches the corner, which is pulled back onto the circle in the same direction
+        let (x, y) = clamp_length(127, -127, 128.);
+        assert!((x as f64).hypot(y as f64) <= 128., "({x}, {y}) is too long");
+        assert_eq!((x, y), (90, -91));
+        // A short one still moves instead of both axes rounding to 0
+        assert_eq!(clamp_length(127, -127, 1.), (0, -1));
+        // A single key is already within it
+        assert_eq!(clamp_length(0, -127, 128.), (0, -127));
+        assert_eq!(clamp_length(127, 0, 128.), (127, 0));
+        assert_eq!(clamp_length(0, 0, 128.), (0, 0));
+    }
+
+    #[test]
+    fn rounding_modes() {
+        use Rounding::*;
+        let cases = [
+            (0.6, [0, 1, 1]),
+            (-0.6, [0, -1, -1]),
+            (0.5, [0, 1, 1]),
+            (-0.5, [0, 0, -1]),
+            (0.4, [0, 0, 0]),
+            (-1.4, [-1, -1, -1]),
+        ];
+        for (value, expected) in cases {
+            for (rounding, expected) in [Truncate, RoundHalfUp, Nearest].into_iter().zip(expected) {
+                assert_eq!(rounding.apply(value), expected, "{value}");
+                assert_eq!(
+                    axis_value(Position::RX, value, rounding),
+                    expected,
+                    "{value}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn triggers_are_pushed_either_way() {
+        for rounding in [Rounding::Truncate, Rounding::RoundHalfUp, Rounding::Nearest] {
+            assert_eq!(axis_value(Position::RZ, 128., rounding), 255);
+            assert_eq!(axis_value(Position::RZ, -127., rounding), 253);
+            assert_eq!(axis_value(Position::RZ, 0.6, rounding), 1);
+            assert_eq!(axis_value(Position::RZ, -0.6, rounding), 1);
+        }
+        // 0.3 from center is 0.598 of the trigger
+        assert_eq!(axis_value(Position::RZ, -0.3, Rounding::Truncate), 0);
+        assert_eq!(axis_value(Position::RZ, -0.3, Rounding::RoundHalfUp), 1);
+        assert_eq!(axis_value(Position::RZ, -0.3, Rounding::Nearest), 1);
+    }
+
+    #[test]
+    fn gain_is_linear_until_the_edge() {
+        assert_eq!(apply_gain((10., -20.), 1., 1.), (10., -20.));
+        assert_eq!(apply_gain((10., -20.), 2., 0.5), (20., -10.));
+        for gain in [0., 0.25, 1., 3., 6.] {
+            let (x, y) = apply_gain((20., -20.), gain, gain);
+            assert_eq!((x, y), (20. * gain, -20. * gain), "gain {gain}");
+        }
+        // Past the edge of the stick it stays there
+        assert_eq!(
+            apply_gain((20., -20.), 10., 10.),
+            (AXIS_MAX as f64, AXIS_MIN as f64)
+        );
+    }
+
+    /// Holds `keys` down and returns where the left stick ends up
+    fn left_stick(state: &mut AppState, keys: &[KeyCode]) -> (i32, i32) {
+        for key in keys {
+            state.key_event(*key, true);
+        }
+        let mut stick = (0, 0);
+        for line in sent(state) {
+            match line.split_once(' ') {
+                Some(("X", value)) => stick.0 = value.parse().unwrap(),
+                Some(("Y", value)) => stick.1 = value.parse().unwrap(),
+                _ => (),
+            }
+        }
+        for key in keys {
+            state.key_event(*key, false);
+        }
+        sent(state);
+        stick
+    }
+
+    #[test]
+    fn left_stick_max_extremes() {
+        use KeyCode::{KeyA, KeyD, KeyS, KeyW};
+        let cases: &[(&str, &[KeyCode], (i32, i32))] = &[
+            ("left_stick_max = 128.0", &[KeyW], (0, -127)),
+            ("left_stick_max = 128.0", &[KeyW, KeyD], (91, -90)),
+            ("left_stick_max = 128.0", &[KeyS, KeyA], (-90, 91)),
+            ("left_stick_max = 128.0", &[KeyS, KeyD], (90, 91)),
+            // W and A only go to 127 while S and D go to 128, so everything held is one off
+            ("left_stick_max = 128.0", &[KeyW, KeyA, KeyS, KeyD], (1, 1)),
+            ("left_stick_max = 1.0", &[KeyW], (0, -1)),
+            ("left_stick_max = 1.0", &[KeyD], (1, 0)),
+            ("left_stick_max = 1.0", &[KeyW, KeyD], (1, 0)),
+            ("left_stick_max = 1.0", &[KeyW, KeyA, KeyS, KeyD], (0, 1)),
+            ("left_stick_max = 128.0\ndiagonal_lock = true", &[KeyW], (90, -90)),
+            ("left_stick_max = 128.0\ndiagonal_lock = true", &[KeyW, KeyD], (90, -91)),
+            // The one left over from W and S snaps onto a diagonal too
+            ("left_stick_max = 128.0\ndiagonal_lock = true", &[KeyW, KeyS], (-1, 1)),
+            (
+                "left_stick_max = 1.0\ndiagonal_lock = true\ninvert_left_x = true\ninvert_left_y = true",
+                &[KeyW],
+                (0, 1),
+            ),
+            (
+                "left_stick_max = 128.0\ninvert_left_x = true\ninvert_left_y = true",
+                &[KeyW, KeyD],
+                (-91, 90),
+            ),
+        ];
+        for (config, keys, expected) in cases {
+            let mut state = state(config);
+            let (x, y) = left_stick(&mut state, keys);
+            assert_eq!((x, y), *expected, "{config:?} holding {keys:?}");
+            let max = state.config.left_stick_max.unwrap();
+            assert!(
+                (x as f64).hypot(y as f64) <= max,
+                "{config:?} holding {keys:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn profile_switch_swaps_the_stick() {
+        // Every setting is different in each profile, so a field left behind by the switch shows
+        let text = r#"
+            [stick]
+            sensitivity = 100.0
+            gain_x = 1.0
+            gain_y = 1.0
+            vertical_scale = 1.5
+            smoothing = 0.0
+            reversal_reset = 0.0
+            center_hold = 2.0
+            deadzone = 1.0
+            anti_deadzone = 0.0
+            recenter_ms = 20
+            # Long enough that the first motion after a recenter is always the baseline
+            recenter_echo_ms = 10000
+            recenter_hold_below = 0.0
+            recenter_hold_ms = 500
+            rounding = "nearest"
+
+            [profiles.slow.stick]
+            sensitivity = 50.0
+            gain_x = 0.5
+            gain_y = 0.75
+            vertical_scale = 2.0
+            smoothing = 0.25
+            smoothing_by_speed = { slow = 1.0, fast = 10.0 }
+            reversal_reset = 3.0
+            center_hold = 1.0
+            deadzone = 2.0
+            anti_deadzone = 5.0
+            recenter_ms = 30
+            recenter_echo_ms = 20000
+            recenter_hold_below = 10.0
+            recenter_hold_ms = 600
+            rounding = "truncate"
+
+            [profiles.fast.stick]
+            sensitivity = 500.0
+            gain_x = 2.0
+            gain_y = 1.25
+            vertical_scale = 1.0
+            smoothing = 0.5
+            smoothing_by_speed = { slow = 2.0, fast = 20.0 }
+            reversal_reset = 5.0
+            center_hold = 3.0
+            deadzone = 4.0
+            anti_deadzone = 10.0
+            recenter_ms = 40
+            recenter_echo_ms = 30000
+            recenter_hold_below = 20.0
+            recenter_hold_ms = 0
+            rounding = "round_half_up"
+        "#;
+        // The cycle is loaded from the config files, so it's filled in below instead
+        let profile = |name: &str| {
+            let config = Config::from_table(text.parse().unwrap(), Some(name), "test.toml");
+            (name.to_string(), Rc::new(config.unwrap()))
+        };
+        let mut state = state(text);
+        state.profile_cycle = vec![profile("slow"), profile("fast")];
+        let motion = |state: &mut AppState| {
+            // Leaving out the recenter the switch sends, and the baseline motion after it
+            state.do_mouse_move((2., 0.));
+            sent(state);
+            state.do_mouse_move((2., 0.));
+            let sent = sent(state);
+            state.recenter_all();
+            sent.into_iter()
+                .find(|line| line.starts_with("RX "))
+                .unwrap()
+        };
+        let expect = |state: &AppState, index: usize| {
+            let stick = &state.profile_cycle[index].1.stick;
+            assert_eq!(state.stick(), stick);
+            assert_eq!(
+                (state.deadzone, state.vertical_scale()),
+                (stick.deadzone, stick.vertical_scale)
+            );
+        };
+
+        assert_eq!(*state.stick(), state.config.stick);
+        let base = motion(&mut state);
+
+        state.cycle_profile();
+        expect(&state, 0);
+        let slow = motion(&mut state);
+
+        state.profile_switched_at = None;
+        state.cycle_profile();
+        expect(&state, 1);
+        let fast = motion(&mut state);
+
+        assert_eq!([base, slow, fast], ["RX 50", "RX 18", "RX 69"]);
+    }
+
+    #[test]
+    fn snapshot_loads_back_with_the_base_settings() {
+        let dir = std::env::temp_dir().join(format!("mouse-con-snapshot-{}", std::process::id()));
+        let path = dir.join("config.toml");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = r#"
+            sensitivity_toggle = { key = "KeyY", alternate = 900.0 }
+
+            [stick]
+            sensitivity = 300.0
+
+            [button_mode]
+            button = 3
+
+            [button_mode.stick]
+            sensitivity = 80.0
+            deadzone = 9.0
+        "#;
+        std::fs::write(&path, text).unwrap();
+
+        let mut state = state(text);
+        state.snapshot_path = Some(path.clone());
+        state.deadzone = 4.;
+        state.vertical_scale = 1.25;
+        // Neither of these is saved, they only last while running
+        state.alternate_sensitivity = true;
+        state.button_mode_held = true;
+        state.save_snapshot();
+
+        let saved = Config::load(std::slice::from_ref(&path), Some("snapshot-1")).unwrap();
+        assert_eq!(saved.stick.sensitivity, 300.);
+        assert_eq!(saved.stick.deadzone, 4.);
+        assert_eq!(saved.stick.vertical_scale, 1.25);
+        let mode = saved.button_mode.unwrap();
+        assert_eq!((mode.stick.sensitivity, mode.stick.deadzone), (80., 9.));
+        // The rest of the file is left alone
+        let base = Config::load(&[path], None).unwrap();
+        assert_eq!((base.stick.sensitivity, base.stick.deadzone), (300., 0.));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn small_stick_is_held_then_recentered() {
+        let mut state = state(
+            r#"
+            [stick]
+            recenter_hold_below = 20.0
+            recenter_hold_ms = 500
+            "#,
+        );
+        let start = Instant::now();
+        state.do_mouse_move((0.01, 0.));
+        assert_eq!(sent(&mut state), ["RX 6", "SYN", "RY 0", "SYN"]);
+
+        // Past recenter_ms it's still held out
+        state.update_timers(start + Duration::from_millis(100));
+        assert!(sent(&mut state).is_empty());
+        state.update_timers(start + Duration::from_millis(550));
+        assert!(sent(&mut state).is_empty());
+        // Until the hold runs out
+        state.update_timers(start + Duration::from_millis(650));
+        assert_eq!(sent(&mut state), ["RX 0", "SYN", "RY 0", "SYN"]);
+    }
+
+    #[test]
+    fn live_tuning_applies_in_button_mode() {
+        let mut state = state(
+            r#"
+            sensitivity_toggle = { key = "KeyY", alternate = 50.0 }
+
+            [stick]
+            sensitivity = 100.0
+            deadzone = 2.0
+            vertical_scale = 1.5
+
+            [button_mode]
+            button = 3
+
+            [button_mode.stick]
+            sensitivity = 400.0
+            deadzone = 10.0
+            vertical_scale = 1.0
+            "#,
+        );
+        state.do_mouse_button(3, true);
+        // The mode's button is only used for switching
+        assert!(sent(&mut state).is_empty());
+        assert_eq!(state.sensitivity(), 400.);
+        assert_eq!(state.effective_deadzone(), 10.);
+        assert_eq!(state.vertical_scale(), 1.);
+
+        state.alternate_sensitivity = true;
+        state.adjust_deadzone(3.);
+        state.adjust_vertical_scale(0.25);
+        assert_eq!(state.sensitivity(), 200.);
+        assert_eq!(state.effective_deadzone(), 13.);
+        assert_eq!(state.vertical_scale(), 1.25);
+
+        state.do_mouse_button(3, false);
+        assert!(sent(&mut state).is_empty());
+        assert_eq!(state.sensitivity(), 50.);
+        assert_eq!(state.effective_deadzone(), 5.);
+        assert_eq!(state.vertical_scale(), 1.75);
+    }
+
+    #[test]
+    fn smoothed_recenter_settles_without_oscillating() {
+        let mut state = state(
+            r#"
+            [stick]
+            smoothing = 0.5
+            center_hold = 2.0
+            "#,
+        );
+        let start = Instant::now();
+        for _ in 0..5 {
+            state.do_mouse_move((-3., 2.));
+        }
+        sent(&mut state);
+
+        run_timers(&mut state, start, Duration::from_secs(1));
+        let recentering = sent(&mut state);
+        for axis in ["RX", "RY"] {
+            let values = axis_values(&recentering, axis);
+            assert!(values.len() > 2, "{axis} {values:?}");
+            assert_eq!(values.last(), Some(&0), "{axis} {values:?}");
+            // Easing back stays on one side of center and never turns back out
+            assert!(
+                values.windows(2).all(|pair| pair[1].abs() <= pair[0].abs()),
+                "{axis} {values:?}"
+            );
+            let side = values[0].signum();
+            assert!(
+                values.iter().all(|value| value.signum() != -side),
+                "{axis} {values:?}"
+            );
+        }
+
+        // Once centered it stays put
+        run_timers(
+            &mut state,
+            start + Duration::from_secs(1),
+            Duration::from_secs(1),
+        );
+        assert!(sent(&mut state).is_empty());
+    }
+
+    #[test]
+    fn direction_bind_doesnt_chatter_around_the_threshold() {
+        let mut state = state(
+            r#"
+            [[direction_binds]]
+            direction = "left"
+            button = "TL"
+            arm = 4.0
+            disarm = 1.0
+            "#,
+        );
+        let mut presses = Vec::new();
+        let motions = [
+            -2., -5., -3., -4.5, -2., -4.5, -1.5, -1., -3., -2., -4.5, 2.,
+        ];
+        for dx in motions {
+            state.do_mouse_move((dx, 0.));
+            for value in axis_values(&sent(&mut state), "TL") {
+                presses.push((dx, value));
+            }
+        }
+        // Pressed past 4, held until 1 or less, and only pressed again past 4
+        assert_eq!(presses, [(-5., 1), (-1., 0), (-4.5, 1), (2., 0)]);
+    }
+
+    #[test]
+    fn composite_binding_order() {
+        let bindings = r#"
+            [bindings]
+            KeyZ = { axis = "Y", value = -127, button = "ThumbL" }
+        "#;
+        for (order, pressed, released) in [
+            (
+                "buttons_first",
+                ["ThumbL 1", "Y -127", "SYN"],
+                ["ThumbL 0", "Y 0", "SYN"],
+            ),
+            (
+                "axes_first",
+                ["Y -127", "ThumbL 1", "SYN"],
+                ["Y 0", "ThumbL 0", "SYN"],
+            ),
+        ] {
+            let mut state = state(&format!("composite_order = \"{order}\"\n{bindings}"));
+            state.key_event(KeyCode::KeyZ, true);
+            // Both go out under one sync
+            assert_eq!(sent(&mut state), pressed, "{order}");
+            state.key_event(KeyCode::KeyZ, false);
+            assert_eq!(sent(&mut state), released, "{order}");
+        }
+    }
+
+    #[test]
+    fn fast_motion_skips_smoothing_by_speed() {
+        // Slow enough that none of these reach the edge of the stick
+        let unsmoothed = "[stick]\nsensitivity = 10.0\n";
+        let smoothed = format!(
+            "{unsmoothed}smoothing = 0.8\nsmoothing_by_speed = {{ slow = 2.0, fast = 20.0 }}"
+        );
+        let first_rx = |text: &str, dx: f64| {
+            let mut state = state(text);
+            state.do_mouse_move((dx, 0.));
+            axis_values(&sent(&mut state), "RX")[0]
+        };
+        let raw = |dx: f64| stick_from_motion((dx, 0.), 10., 1.5).0;
+        assert!(raw(25.) < AXIS_MAX as f64);
+
+        // From rest a fast flick comes through whole, a slow motion is held back by the smoothing
+        assert_eq!(first_rx(&smoothed, 25.), first_rx(unsmoothed, 25.));
+        assert_eq!(first_rx(&smoothed, 1.), (raw(1.) * 0.2).round() as i32);
+        // Halfway between slow and fast it gets half of the smoothing
+        assert_eq!(first_rx(&smoothed, 11.), (raw(11.) * 0.6).round() as i32);
+    }
+
+    #[test]
+    fn camera_lock_freezes_the_stick() {
+        for motion in ["ignore", "accumulate"] {
+            let mut state = state(&format!(
+                "camera_lock_key = \"KeyO\"\ncamera_lock_motion = \"{motion}\"\n[stick]\nsensitivity = 10.0"
+            ));
+            let raw = |dx: f64| stick_from_motion((dx, 0.), 10., 1.5).0.round() as i32;
+            let start = Instant::now();
+            state.do_mouse_move((2., 0.));
+            assert_eq!(axis_values(&sent(&mut state), "RX"), [raw(2.)], "{motion}");
+
+            // Held where it was, neither moved nor recentered
+            state.key_event(KeyCode::KeyO, true);
+            state.do_mouse_move((1., 0.));
+            state.do_mouse_move((3., 0.));
+            run_timers(&mut state, start, Duration::from_secs(1));
+            assert!(sent(&mut state).is_empty(), "{motion}");
+
+            let later = start + Duration::from_secs(1);
+            state.key_event(KeyCode::KeyO, false);
+            let unlocked = axis_values(&sent(&mut state), "RX");
+            run_timers(&mut state, later, Duration::from_secs(1));
+            let recentered = axis_values(&sent(&mut state), "RX");
+            if motion == "accumulate" {
+                // What the mouse did meanwhile is sent as one motion
+                assert_eq!(unlocked, [raw(4.)], "{motion}");
+            } else {
+                assert!(unlocked.is_empty(), "{motion}");
+            }
+            assert_eq!(recentered, [0], "{motion}");
+        }
+    }
+
+    #[test]
+    fn no_flick_right_after_a_recenter() {
+        for echo_ms in [0, 60_000] {
+            let mut state = state(&format!("[stick]\nrecenter_echo_ms = {echo_ms}"));
+            let start = Instant::now();
+            state.do_mouse_move((0.01, 0.));
+            run_timers(&mut state, start, Duration::from_millis(100));
+            assert_eq!(axis_values(&sent(&mut state), "RX"), [6, 0]);
+
+            // The first motion after the recenter is only a new starting point
+            state.do_mouse_move((0.01, 0.));
+            let echo = axis_values(&sent(&mut state), "RX");
+            state.do_mouse_move((0.01, 0.));
+            let next = axis_values(&sent(&mut state), "RX");
+            let expected_echo = if echo_ms > 0 { vec![] } else { vec![6] };
+            assert_eq!((echo, next), (expected_echo, vec![6]), "{echo_ms}");
+        }
+    }
+
+    #[test]
+    fn rapid_profile_cycle_presses_switch_once() {
+        // The cycle is loaded from the config files, so this one needs a real file
+        let dir = std::env::temp_dir().join(format!("mouse-con-cycle-{}", std::process::id()));
+        let path = dir.join("config.toml");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            &path,
+            r#"
+            profile_cycle_key = "F9"
+            profile_cycle = ["a", "b", "c"]
+            profile_switch_debounce_ms = 300
+
+            [profiles.a]
+            [profiles.b]
+            [profiles.c]
+            "#,
+        )
+        .unwrap();
+        let args = Args {
+            config_paths: vec![path],
+            mock_device: true,
+            ..Args::default()
+        };
+        let config = Config::load(&args.config_paths, None).unwrap();
+        let mut state = AppState::new(config, &args).unwrap();
+        state.device = Device::Recording(Default::default());
+        state.headless = true;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Bouncing, double tapping and autorepeat
+        for _ in 0..3 {
+            state.key_event(KeyCode::F9, true);
+            state.key_event(KeyCode::F9, true);
+            state.key_event(KeyCode::F9, false);
+        }
+        assert_eq!(state.profile_index, Some(0));
+
+        // Once the debounce is over the next press goes on to the next one
+        state.profile_switched_at = Some(Instant::now() - Duration::from_millis(300));
+        state.key_event(KeyCode::F9, true);
+        state.key_event(KeyCode::F9, false);
+        state.key_event(KeyCode::F9, true);
+        assert_eq!(state.profile_index, Some(1));
+        assert_eq!(state.profile_cycle[1].0, "b");
+    }
+
+    #[test]
+    fn layer_button_goes_around_the_switch() {
+        let mut state = state(
+            r#"
+            [layer]
+            key = "Tab"
+            mode = "hold"
+            button = "ThumbL"
+
+            [layer.bindings]
+            KeyQ = { button = "Select" }
+            "#,
+        );
+        state.key_event(KeyCode::KeyQ, true);
+        assert_eq!(sent(&mut state), ["TL 1", "SYN"]);
+        // Q is let go of from the old layer before the layer's button goes down
+        state.key_event(KeyCode::Tab, true);
+        assert_eq!(sent(&mut state), ["TL 0", "SYN", "ThumbL 1", "SYN"]);
+        // It takes a fresh press to use the layer's Q
+        state.key_event(KeyCode::KeyQ, false);
+        assert!(sent(&mut state).is_empty());
+        state.key_event(KeyCode::KeyQ, true);
+        assert_eq!(sent(&mut state), ["Select 1", "SYN"]);
+        // and the button comes up before the layer's Q is let go of
+        state.key_event(KeyCode::Tab, false);
+        assert_eq!(sent(&mut state), ["ThumbL 0", "SYN", "Select 0", "SYN"]);
+        state.key_event(KeyCode::KeyQ, false);
+        assert!(sent(&mut state).is_empty());
+    }
+
+    #[test]
+    fn reversal_resets_smoothing_for_that_axis() {
+        let last = |reversal_reset: f64, motions: &[(f64, f64)]| {
+            let mut state = state(&format!(
+                "[stick]\nsensitivity = 10.0\nsmoothing = 0.8\nreversal_reset = {reversal_reset}"
+            ));
+            for motion in motions {
+                state.do_mouse_move(*motion);
+            }
+            let sent = sent(&mut state);
+            (
+                *axis_values(&sent, "RX").last().unwrap(),
+                *axis_values(&sent, "RY").last().unwrap(),
+            )
+        };
+        let raw = |dx: f64| stick_from_motion((dx, 0.), 10., 1.5).0.round() as i32;
+        let steady = [(3., 1.); 10];
+
+        // Turning X around sharply jumps straight to the new motion, Y keeps easing along
+        let flick = [&steady[..], &[(-3., 1.)]].concat();
+        let (x, y) = last(3., &flick);
+        assert_eq!(x, raw(-3.));
+        assert_eq!(y, last(0., &flick).1);
+        // Without it, or for a reversal shorter than reversal_reset, X is still smoothed
+        assert!(last(0., &flick).0 > 0);
+        let nudge = [&steady[..], &[(-2., 1.)]].concat();
+        assert!(last(3., &nudge).0 > 0);
+    }
+
+    #[test]
+    fn first_motion_after_unpausing_is_a_baseline() {
+        for baseline in [true, false] {
+            // Pausing recenters, which would hold back the next motion on its own
+            let mut state = state(&format!(
+                "unpause_baseline = {baseline}\n[stick]\nrecenter_echo_ms = 0"
+            ));
+            state.toggle_active();
+            state.toggle_active();
+            sent(&mut state);
+
+            state.do_mouse_move((0.01, 0.));
+            let first = axis_values(&sent(&mut state), "RX");
+            state.do_mouse_move((0.01, 0.));
+            let second = axis_values(&sent(&mut state), "RX");
+            let expected_first = if baseline { vec![] } else { vec![6] };
+            assert_eq!((first, second), (expected_first, vec![6]), "{baseline}");
+        }
+    }
+
+    #[test]
+    fn recenter_lands_on_center_despite_anti_deadzone() {
+        for smoothing in [0., 0.5] {
+            let mut state = state(&format!(
+                "[stick]\nanti_deadzone = 20.0\nsmoothing = {smoothing}"
+            ));
+            let start = Instant::now();
+            state.do_mouse_move((0.01, -0.01));
+            let moved = sent(&mut state);
+            // Pushed out past the game's deadzone, 20 along the diagonal
+            for axis in ["RX", "RY"] {
+                assert!(
+                    axis_values(&moved, axis)[0].abs() >= 14,
+                    "{smoothing} {moved:?}"
+                );
+            }
+
+            run_timers(&mut state, start, Duration::from_secs(1));
+            let recentered = sent(&mut state);
+            for axis in ["RX", "RY"] {
+                assert_eq!(
+                    axis_values(&recentered, axis).last(),
+                    Some(&0),
+                    "{smoothing}"
+                );
+            }
+        }
+    }
+
+    /// A state that sends with `send_full = "coalesce"`, with the device full for the next
+    /// `full_for` events
+    fn coalescing(full_for: usize) -> AppState {
+        let state = state("send_full = \"coalesce\"\n[stick]\nsensitivity = 10.0");
+        recorded(&state).lock().unwrap().full_for = full_for;
+        state
+    }
+
+    /// The events sent since the last call, leaving out the syncs
+    fn sent_events(state: &mut AppState) -> Vec<String> {
+        let mut events = sent(state);
+        events.retain(|line| line != "SYN");
+        events
+    }
+
+    #[test]
+    fn coalesce_keeps_a_press_and_release_while_full() {
+        let mut state = coalescing(2);
+        let start = Instant::now();
+        state.key_event(KeyCode::KeyC, true);
+        state.key_event(KeyCode::KeyC, false);
+        // Neither got through, and the release waits behind the press instead of going first
+        assert!(sent_events(&mut state).is_empty());
+        assert_eq!(state.pending_events.len(), 2);
+
+        run_timers(&mut state, start, Duration::from_millis(50));
+        assert_eq!(sent_events(&mut state), ["B 1", "B 0"]);
+        assert!(state.pending_events.is_empty());
+        assert_eq!(state.send_stats.coalesced, 0);
+    }
+
+    #[test]
+    fn coalesce_keeps_order_when_a_flush_fails_twice() {
+        // The press, the flushes before the release and the next press, and the timer's first
+        // flush all fail
+        let mut state = coalescing(4);
+        state.key_event(KeyCode::KeyC, true);
+        state.key_event(KeyCode::KeyC, false);
+        state.key_event(KeyCode::ShiftLeft, true);
+        let at = Instant::now() + Duration::from_millis(10);
+        state.update_timers(at);
+        assert!(sent_events(&mut state).is_empty());
+        assert!(state.flush_pending_at.is_some());
+
+        state.update_timers(at + Duration::from_millis(10));
+        assert_eq!(sent_events(&mut state), ["B 1", "B 0", "A 1"]);
+        assert!(state.flush_pending_at.is_none());
+
+        // With room again new events go straight out
+        state.key_event(KeyCode::ShiftLeft, false);
+        assert_eq!(sent_events(&mut state), ["A 0"]);
+    }
+
+    #[test]
+    fn coalesce_only_merges_axes() {
+        // Full until the third motion's RY
+        let mut state = coalescing(6);
+        let start = Instant::now();
+        state.do_mouse_move((1., 0.));
+        state.do_mouse_move((2., 0.));
+        state.key_event(KeyCode::KeyC, true);
+        state.do_mouse_move((3., 0.));
+        run_timers(&mut state, start, Duration::from_millis(10));
+
+        let raw = |dx: f64| stick_from_motion((dx, 0.), 10., 1.5).0.round() as i32;
+        // The second motion replaced the first, while the third one waited behind the button
+        // instead of replacing it too
+        assert_eq!(
+            sent_events(&mut state),
+            [
+                format!("RX {}", raw(2.)),
+                "RY 0".to_string(),
+                "B 1".to_string(),
+                format!("RX {}", raw(3.)),
+                "RY 0".to_string(),
+            ]
+        );
+        assert_eq!(state.send_stats.coalesced, 2);
+    }
 }
@@ -0,0 +1,557 @@
+use serde::{Deserialize, Serialize};
+use uinput::event::{
+    absolute::Position,
+    controller::{DPad, GamePad},
+    Absolute, Controller,
+};
+use winit::keyboard::KeyCode;
+
+use crate::device;
+
+macro_rules! key_names {
+    ($($key:ident),* $(,)?) => {
+        /// Every keyboard key that can be used in the config, by name
+        pub const KEY_NAMES: &[(&str, KeyCode)] = &[$((stringify!($key), KeyCode::$key)),*];
+    };
+}
+
+key_names![
+    Backquote,
+    Backslash,
+    BracketLeft,
+    BracketRight,
+    Comma,
+    Digit0,
+    Digit1,
+    Digit2,
+    Digit3,
+    Digit4,
+    Digit5,
+    Digit6,
+    Digit7,
+    Digit8,
+    Digit9,
+    Equal,
+    IntlBackslash,
+    IntlRo,
+    IntlYen,
+    KeyA,
+    KeyB,
+    KeyC,
+    KeyD,
+    KeyE,
+    KeyF,
+    KeyG,
+    KeyH,
+    KeyI,
+    KeyJ,
+    KeyK,
+    KeyL,
+    KeyM,
+    KeyN,
+    KeyO,
+    KeyP,
+    KeyQ,
+    KeyR,
+    KeyS,
+    KeyT,
+    KeyU,
+    KeyV,
+    KeyW,
+    KeyX,
+    KeyY,
+    KeyZ,
+    Minus,
+    Period,
+    Quote,
+    Semicolon,
+    Slash,
+    AltLeft,
+    AltRight,
+    Backspace,
+    CapsLock,
+    ContextMenu,
+    ControlLeft,
+    ControlRight,
+    Enter,
+    SuperLeft,
+    SuperRight,
+    ShiftLeft,
+    ShiftRight,
+    Space,
+    Tab,
+    Convert,
+    KanaMode,
+    Lang1,
+    Lang2,
+    Lang3,
+    Lang4,
+    Lang5,
+    NonConvert,
+    Delete,
+    End,
+    Help,
+    Home,
+    Insert,
+    PageDown,
+    PageUp,
+    ArrowDown,
+    ArrowLeft,
+    ArrowRight,
+    ArrowUp,
+    NumLock,
+    Numpad0,
+    Numpad1,
+    Numpad2,
+    Numpad3,
+    Numpad4,
+    Numpad5,
+    Numpad6,
+    Numpad7,
+    Numpad8,
+    Numpad9,
+    NumpadAdd,
+    NumpadBackspace,
+    NumpadClear,
+    NumpadClearEntry,
+    NumpadComma,
+    NumpadDecimal,
+    NumpadDivide,
+    NumpadEnter,
+    NumpadEqual,
+    NumpadHash,
+    NumpadMemoryAdd,
+    NumpadMemoryClear,
+    NumpadMemoryRecall,
+    NumpadMemoryStore,
+    NumpadMemorySubtract,
+    NumpadMultiply,
+    NumpadParenLeft,
+    NumpadParenRight,
+    NumpadStar,
+    NumpadSubtract,
+    Escape,
+    Fn,
+    FnLock,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+    BrowserBack,
+    BrowserFavorites,
+    BrowserForward,
+    BrowserHome,
+    BrowserRefresh,
+    BrowserSearch,
+    BrowserStop,
+    Eject,
+    LaunchApp1,
+    LaunchApp2,
+    LaunchMail,
+    MediaPlayPause,
+    MediaSelect,
+    MediaStop,
+    MediaTrackNext,
+    MediaTrackPrevious,
+    Power,
+    Sleep,
+    AudioVolumeDown,
+    AudioVolumeMute,
+    AudioVolumeUp,
+    WakeUp,
+    Meta,
+    Hyper,
+    Turbo,
+    Abort,
+    Resume,
+    Suspend,
+    Again,
+    Copy,
+    Cut,
+    Find,
+    Open,
+    Paste,
+    Props,
+    Select,
+    Undo,
+    Hiragana,
+    Katakana,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    F13,
+    F14,
+    F15,
+    F16,
+    F17,
+    F18,
+    F19,
+    F20,
+    F21,
+    F22,
+    F23,
+    F24,
+    F25,
+    F26,
+    F27,
+    F28,
+    F29,
+    F30,
+    F31,
+    F32,
+    F33,
+    F34,
+    F35,
+];
+
+/// Other names keys are commonly written as, in the form `normalize_key_name` gives
+const KEY_ALIASES: &[(&str, KeyCode)] = &[
+    ("lshift", KeyCode::ShiftLeft),
+    ("rshift", KeyCode::ShiftRight),
+    ("lctrl", KeyCode::ControlLeft),
+    ("rctrl", KeyCode::ControlRight),
+    ("ctrlleft", KeyCode::ControlLeft),
+    ("ctrlright", KeyCode::ControlRight),
+    ("lalt", KeyCode::AltLeft),
+    ("ralt", KeyCode::AltRight),
+    ("lsuper", KeyCode::SuperLeft),
+    ("rsuper", KeyCode::SuperRight),
+    ("lwin", KeyCode::SuperLeft),
+    ("rwin", KeyCode::SuperRight),
+    ("esc", KeyCode::Escape),
+    ("return", KeyCode::Enter),
+    ("del", KeyCode::Delete),
+    ("ins", KeyCode::Insert),
+    ("pgup", KeyCode::PageUp),
+    ("pgdn", KeyCode::PageDown),
+    ("pgdown", KeyCode::PageDown),
+    ("caps", KeyCode::CapsLock),
+    ("up", KeyCode::ArrowUp),
+    ("down", KeyCode::ArrowDown),
+    ("left", KeyCode::ArrowLeft),
+    ("right", KeyCode::ArrowRight),
+    ("grave", KeyCode::Backquote),
+    ("tilde", KeyCode::Backquote),
+    ("equals", KeyCode::Equal),
+    ("dash", KeyCode::Minus),
+];
+
+/// Lower cases a key name and drops separators, and puts a leading `left`/`right` at the end
+/// like winit does (`LeftShift` to `shiftleft`)
+fn normalize_key_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .filter(|c| !matches!(c, '_' | '-' | ' '))
+        .flat_map(char::to_lowercase)
+        .collect();
+    for side in ["left", "right"] {
+        if let Some(rest) = name.strip_prefix(side).filter(|rest| !rest.is_empty()) {
+            return format!("{rest}{side}");
+        }
+    }
+    name
+}
+
+/// Finds a key by its winit name, ignoring case and separators, or by one of the common aliases.
+/// Single letters and digits work too, like `w` for `KeyW`.
+pub fn parse_key(name: &str) -> Option<KeyCode> {
+    if let Some((_, key)) = KEY_NAMES.iter().find(|(key_name, _)| *key_name == name) {
+        return Some(*key);
+    }
+
+    let normalized = normalize_key_name(name);
+    let by_name = |wanted: &str| {
+        KEY_NAMES
+            .iter()
+            .find(|(key_name, _)| normalize_key_name(key_name) == wanted)
+            .map(|(_, key)| *key)
+    };
+    by_name(&normalized)
+        .or_else(|| {
+            KEY_ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == normalized)
+                .map(|(_, key)| *key)
+        })
+        .or_else(|| match normalized.as_bytes() {
+            [c] if c.is_ascii_lowercase() => by_name(&format!("key{normalized}")),
+            [c] if c.is_ascii_digit() => by_name(&format!("digit{normalized}")),
+            _ => None,
+        })
+}
+
+/// The error for a key name that doesn't parse, suggesting the closest one if it's near enough
+/// to probably be a typo
+pub fn unknown_key_message(name: &str) -> String {
+    let normalized = normalize_key_name(name);
+    let closest = KEY_NAMES
+        .iter()
+        .map(|(key_name, _)| {
+            (
+                edit_distance(&normalize_key_name(key_name), &normalized),
+                key_name,
+            )
+        })
+        .min_by_key(|(distance, _)| *distance);
+    match closest {
+        Some((distance, key_name)) if distance <= 2.max(normalized.len() / 4) => {
+            format!("unknown key `{name}`, did you mean `{key_name}`?")
+        }
+        _ => format!("unknown key `{name}`, run with --list-keys to see them all"),
+    }
+}
+
+/// How many single character insertions, deletions or substitutions turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(a_char != *b_char);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+pub fn key_name(key: KeyCode) -> &'static str {
+    KEY_NAMES
+        .iter()
+        .find(|(_, named_key)| *named_key == key)
+        .map_or("unknown key", |(name, _)| name)
+}
+
+/// A keyboard key written by name in the config
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "&str")]
+pub struct Key(pub KeyCode);
+
+impl From<Key> for &str {
+    fn from(key: Key) -> Self {
+        key_name(key.0)
+    }
+}
+
+impl TryFrom<String> for Key {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        parse_key(&name)
+            .map(Key)
+            .ok_or_else(|| unknown_key_message(&name))
+    }
+}
+
+/// Every controller axis that can be used in the config, by name
+pub const AXIS_NAMES: &[(&str, Position)] = &[
+    ("X", Position::X),
+    ("Y", Position::Y),
+    ("RX", Position::RX),
+    ("RY", Position::RY),
+    ("Z", Position::Z),
+    ("RZ", Position::RZ),
+];
+
+pub fn parse_axis(name: &str) -> Option<Position> {
+    AXIS_NAMES
+        .iter()
+        .find(|(axis_name, _)| *axis_name == name)
+        .map(|(_, axis)| *axis)
+}
+
+pub fn axis_name(axis: Position) -> &'static str {
+    AXIS_NAMES
+        .iter()
+        .find(|(_, named_axis)| *named_axis == axis)
+        .map_or("unknown axis", |(name, _)| name)
+}
+
+/// A controller axis written by name in the config
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "&str")]
+pub struct Axis(pub Position);
+
+impl From<Axis> for &str {
+    fn from(axis: Axis) -> Self {
+        axis_name(axis.0)
+    }
+}
+
+impl TryFrom<String> for Axis {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        parse_axis(&name)
+            .map(Axis)
+            .ok_or_else(|| format!("unknown axis `{name}`"))
+    }
+}
+
+/// Every controller button that can be used in the config, by name
+pub const BUTTON_NAMES: &[(&str, Controller)] = &[
+    ("A", Controller::GamePad(GamePad::A)),
+    ("B", Controller::GamePad(GamePad::B)),
+    ("X", Controller::GamePad(GamePad::X)),
+    ("Y", Controller::GamePad(GamePad::Y)),
+    ("TL", Controller::GamePad(GamePad::TL)),
+    ("TR", Controller::GamePad(GamePad::TR)),
+    ("TL2", Controller::GamePad(GamePad::TL2)),
+    ("TR2", Controller::GamePad(GamePad::TR2)),
+    ("Select", Controller::GamePad(GamePad::Select)),
+    ("Start", Controller::GamePad(GamePad::Start)),
+    ("Mode", Controller::GamePad(GamePad::Mode)),
+    ("ThumbL", Controller::GamePad(GamePad::ThumbL)),
+    ("ThumbR", Controller::GamePad(GamePad::ThumbR)),
+    ("DPadUp", Controller::DPad(DPad::Up)),
+    ("DPadDown", Controller::DPad(DPad::Down)),
+    ("DPadLeft", Controller::DPad(DPad::Left)),
+    ("DPadRight", Controller::DPad(DPad::Right)),
+];
+
+pub fn parse_button(name: &str) -> Option<Controller> {
+    if let Some(number) = name.strip_prefix("Button") {
+        let index = number.parse::<usize>().ok()?.checked_sub(1)?;
+        return device::generic_buttons().nth(index);
+    }
+
+    BUTTON_NAMES
+        .iter()
+        .find(|(button_name, _)| *button_name == name)
+        .map(|(_, button)| *button)
+}
+
+pub fn button_name(button: Controller) -> String {
+    if let Some(index) = device::generic_buttons().position(|generic| generic == button) {
+        return format!("Button{}", index + 1);
+    }
+
+    BUTTON_NAMES
+        .iter()
+        .find(|(_, named_button)| *named_button == button)
+        .map_or("unknown button", |(name, _)| name)
+        .to_string()
+}
+
+/// The mouse buttons the config can refer to by number, e.g. in `button_mode.button`, as X11 numbers them
+pub const MOUSE_BUTTON_NAMES: &[(u32, &str)] = &[
+    (1, "left"),
+    (2, "middle"),
+    (3, "right"),
+    (8, "back"),
+    (9, "forward"),
+];
+
+/// The config name of a controller event, for printing it
+pub fn event_name(event: uinput::Event) -> String {
+    match event {
+        uinput::Event::Absolute(Absolute::Position(position)) => axis_name(position).to_string(),
+        uinput::Event::Controller(button) => button_name(button),
+        _ => "unknown event".to_string(),
+    }
+}
+
+/// A controller button written by name in the config
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Button(pub Controller);
+
+impl From<Button> for String {
+    fn from(button: Button) -> Self {
+        button_name(button.0)
+    }
+}
+
+impl TryFrom<String> for Button {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        parse_button(&name)
+            .map(Button)
+            .ok_or_else(|| format!("unknown button `{name}`"))
+    }
+}
+
+/// Prints every name the config accepts, for `--list-keys`
+pub fn print_names() {
+    println!("Keyboard keys:");
+    for (name, _) in KEY_NAMES {
+        println!("    {name}");
+    }
+
+    println!();
+    println!("Controller buttons:");
+    for (name, _) in BUTTON_NAMES {
+        println!("    {name}");
+    }
+
+    println!(
+        "    Button1 to Button{} (numbered buttons, see the generic device)",
+        device::MAX_GENERIC_BUTTONS
+    );
+
+    println!();
+    println!("Mouse buttons:");
+    for (number, name) in MOUSE_BUTTON_NAMES {
+        match crate::mouse_button_to_controller_event(*number) {
+            Some(button) => println!("    {number} ({name}, sends {})", button_name(button)),
+            None => println!("    {number} ({name})"),
+        }
+    }
+
+    println!();
+    println!("Controller axes:");
+    for (name, _) in AXIS_NAMES {
+        println!("    {name}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_names_ignore_case_separators_and_side_order() {
+        for name in [
+            "ShiftLeft",
+            "lshift",
+            "LeftShift",
+            "shift_left",
+            "SHIFTLEFT",
+            "left-shift",
+        ] {
+            assert_eq!(parse_key(name), Some(KeyCode::ShiftLeft), "{name}");
+        }
+        assert_eq!(parse_key("w"), Some(KeyCode::KeyW));
+        assert_eq!(parse_key("W"), Some(KeyCode::KeyW));
+        assert_eq!(parse_key("3"), Some(KeyCode::Digit3));
+        assert_eq!(parse_key("up"), Some(KeyCode::ArrowUp));
+        assert_eq!(parse_key("shift"), None);
+    }
+
+    #[test]
+    fn unknown_keys_suggest_the_closest_name() {
+        assert_eq!(
+            unknown_key_message("ShiftLeftt"),
+            "unknown key `ShiftLeftt`, did you mean `ShiftLeft`?"
+        );
+        assert_eq!(
+            unknown_key_message("bracket_rihgt"),
+            "unknown key `bracket_rihgt`, did you mean `BracketRight`?"
+        );
+        assert_eq!(
+            unknown_key_message("launchrocket"),
+            "unknown key `launchrocket`, run with --list-keys to see them all"
+        );
+    }
+}
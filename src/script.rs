@@ -0,0 +1,131 @@
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use winit::{event::MouseScrollDelta, keyboard::KeyCode};
+
+use crate::{cli::Args, config::Config, names, AppState};
+
+/// One line of a `--script` file
+enum Command {
+    Key(KeyCode, bool),
+    Button(u32, bool),
+    MouseMove(f64, f64),
+    Wheel(f64),
+    Wait(Duration),
+}
+
+/// Headless `--script` mode. Inputs are read from a file instead of being captured, so bugs can be
+/// reproduced and the mapping tested without a display. Each line is one of:
+///
+/// - `keydown <key>` / `keyup <key>`, with the key names the config uses
+/// - `buttondown <n>` / `buttonup <n>` for mouse buttons
+/// - `mousemove <dx> <dy>`
+/// - `wheel <notches>`
+/// - `wait <ms>`, timers like the recenter keep running meanwhile
+///
+/// Blank lines and everything after a `#` are ignored.
+pub fn run(path: &Path, config: Config, args: &Args) -> anyhow::Result<()> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let commands = parse(&text).with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    let mut state = AppState::new(config, args)?;
+    state.hide_mouse(true);
+    state.recenter_all();
+
+    for command in commands {
+        state.update_timers(Instant::now());
+        state.feed_watchdog();
+        match command {
+            Command::Key(key, pressed) => {
+                if !state.key_event(key, pressed) {
+                    return Ok(());
+                }
+            }
+            Command::Button(button, pressed) => state.do_mouse_button(button, pressed),
+            Command::MouseMove(dx, dy) => state.do_mouse_move((dx, dy)),
+            Command::Wheel(notches) => {
+                state.do_mouse_wheel(MouseScrollDelta::LineDelta(0., notches as f32))
+            }
+            Command::Wait(duration) => wait(&mut state, duration),
+        }
+    }
+
+    state.hide_mouse(false);
+    state.report_send_stats();
+    Ok(())
+}
+
+/// Sleeps for `duration` while running whatever timers come due in the meantime
+fn wait(state: &mut AppState, duration: Duration) {
+    let until = Instant::now() + duration;
+    loop {
+        let now = Instant::now();
+        state.update_timers(now);
+        if now >= until {
+            break;
+        }
+
+        let wake = state.next_timer().map_or(until, |at| at.min(until));
+        std::thread::sleep(wake.saturating_duration_since(now));
+    }
+}
+
+fn parse(text: &str) -> anyhow::Result<Vec<Command>> {
+    let mut commands = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let words: Vec<_> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+
+        let command = parse_command(&words).with_context(|| format!("line {}", i + 1))?;
+        commands.push(command);
+    }
+
+    Ok(commands)
+}
+
+fn parse_command(words: &[&str]) -> anyhow::Result<Command> {
+    let (name, params) = (words[0], &words[1..]);
+    let param_count = match name {
+        "keydown" | "keyup" | "buttondown" | "buttonup" | "wheel" | "wait" => 1,
+        "mousemove" => 2,
+        _ => anyhow::bail!("unknown command `{name}`"),
+    };
+    anyhow::ensure!(
+        params.len() == param_count,
+        "`{name}` takes {param_count} argument(s), got {}",
+        params.len()
+    );
+
+    let number = |word: &str| -> anyhow::Result<f64> {
+        word.parse()
+            .with_context(|| format!("expected a number, got `{word}`"))
+    };
+    let key = |word: &str| names::parse_key(word).with_context(|| names::unknown_key_message(word));
+    let button = |word: &str| -> anyhow::Result<u32> {
+        word.parse()
+            .with_context(|| format!("expected a mouse button number, got `{word}`"))
+    };
+
+    Ok(match name {
+        "keydown" => Command::Key(key(params[0])?, true),
+        "keyup" => Command::Key(key(params[0])?, false),
+        "buttondown" => Command::Button(button(params[0])?, true),
+        "buttonup" => Command::Button(button(params[0])?, false),
+        "mousemove" => Command::MouseMove(number(params[0])?, number(params[1])?),
+        "wheel" => Command::Wheel(number(params[0])?),
+        "wait" => {
+            let ms: u64 = params[0].parse().with_context(|| {
+                format!("expected a number of milliseconds, got `{}`", params[0])
+            })?;
+            Command::Wait(Duration::from_millis(ms))
+        }
+        _ => unreachable!("unknown commands are rejected above"),
+    })
+}
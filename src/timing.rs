@@ -0,0 +1,57 @@
+use std::time::{Duration, Instant};
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Collects how often mouse motion arrives and how long it takes to handle, for `--timing`
+#[derive(Default)]
+pub struct Timing {
+    last_motion: Option<Instant>,
+    report_at: Option<Instant>,
+    gap_min: Duration,
+    gap_max: Duration,
+    gap_total: Duration,
+    gaps: u32,
+    process_total: Duration,
+    processed: u32,
+}
+
+impl Timing {
+    pub fn record_motion(&mut self, arrived: Instant, processing: Duration) {
+        if let Some(last_motion) = self.last_motion {
+            let gap = arrived - last_motion;
+            if self.gaps == 0 || gap < self.gap_min {
+                self.gap_min = gap;
+            }
+            self.gap_max = self.gap_max.max(gap);
+            self.gap_total += gap;
+            self.gaps += 1;
+        }
+        self.last_motion = Some(arrived);
+        self.process_total += processing;
+        self.processed += 1;
+
+        let report_at = *self.report_at.get_or_insert(arrived + REPORT_INTERVAL);
+        if arrived >= report_at {
+            self.report();
+            self.report_at = Some(arrived + REPORT_INTERVAL);
+        }
+    }
+
+    fn report(&mut self) {
+        if self.gaps > 0 {
+            eprintln!(
+                "Mouse motion gap min/avg/max: {:?}/{:?}/{:?}, avg processing: {:?} ({} events)",
+                self.gap_min,
+                self.gap_total / self.gaps,
+                self.gap_max,
+                self.process_total / self.processed,
+                self.processed,
+            );
+        }
+
+        *self = Self {
+            last_motion: self.last_motion,
+            ..Self::default()
+        };
+    }
+}